@@ -0,0 +1,276 @@
+use crate::{config::get_data_dir, report, trust::hash_script, FetchedScript, ScriptSource};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    env,
+    ffi::CStr,
+    path::PathBuf,
+    process,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    fs::{self, OpenOptions},
+    io::AsyncWriteExt,
+};
+
+const AUDIT_FILE: &str = "audit.jsonl";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditAction {
+    Run,
+    Import,
+}
+
+/// A single executed or imported script, written as one line of JSON.
+/// Runs are written when they start and again when they end, both lines
+/// sharing the same `id`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuditRecord {
+    /// Identifies the lines belonging to the same run, empty in old logs
+    #[serde(default)]
+    pub id: String,
+    /// RFC 3339 timestamp of when the script was started
+    pub timestamp: String,
+    pub action: AuditAction,
+    pub user: String,
+    pub host: String,
+    pub repo: String,
+    #[serde(rename = "ref")]
+    pub rref: String,
+    pub script: String,
+    pub commit: String,
+    pub sha256: String,
+    pub args: Vec<String>,
+    /// Exit code of the script, if it was run and exited normally
+    pub exit_code: Option<i32>,
+    pub duration_ms: u64,
+    /// Whether the script ended, `false` if rem was killed while it ran
+    #[serde(default = "default_finished")]
+    pub finished: bool,
+}
+
+fn default_finished() -> bool {
+    true
+}
+
+impl AuditRecord {
+    pub fn new(
+        action: AuditAction,
+        src: &ScriptSource,
+        script: &FetchedScript,
+        args: &[String],
+    ) -> Self {
+        let now = SystemTime::now();
+        let nanos = now
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        Self {
+            id: format!("{}-{}", process::id(), nanos),
+            timestamp: humantime::format_rfc3339_seconds(now).to_string(),
+            action,
+            user: get_user(),
+            host: get_host(),
+            repo: src.repo.clone(),
+            rref: src.get_ref(),
+            script: src.script_name.clone(),
+            commit: script.commit.clone(),
+            sha256: hash_script(&script.contents),
+            args: args.to_vec(),
+            exit_code: None,
+            duration_ms: 0,
+            finished: false,
+        }
+    }
+
+    /// Marks the record as ended after `duration`, with the script's exit code
+    pub fn finish(&mut self, duration: Duration, exit_code: Option<i32>) {
+        self.duration_ms = duration.as_millis() as u64;
+        self.exit_code = exit_code;
+        self.finished = true;
+    }
+
+    /// The script as it would be passed to `rem run`, with the resolved ref
+    pub fn script_ref(&self) -> String {
+        format!("{}@{}:{}", self.repo, self.rref, self.script)
+    }
+
+    pub fn started_at(&self) -> Option<SystemTime> {
+        humantime::parse_rfc3339(&self.timestamp).ok()
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration::from_millis(self.duration_ms)
+    }
+}
+
+fn get_user() -> String {
+    if let Ok(user) = env::var("USER") {
+        return user;
+    }
+
+    let uid = unsafe { libc::getuid() };
+    let passwd = unsafe { libc::getpwuid(uid) };
+    if passwd.is_null() {
+        return uid.to_string();
+    }
+
+    unsafe { CStr::from_ptr((*passwd).pw_name) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn get_host() -> String {
+    let mut buf = [0 as libc::c_char; 256];
+    let res = unsafe { libc::gethostname(buf.as_mut_ptr(), buf.len()) };
+    if res != 0 {
+        return String::new();
+    }
+
+    unsafe { CStr::from_ptr(buf.as_ptr()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Returns the configured audit log, or `audit.jsonl` in the data dir
+async fn get_log_path(configured: Option<&PathBuf>) -> Result<PathBuf> {
+    if let Some(path) = configured {
+        return Ok(path.clone());
+    }
+
+    let mut path = get_data_dir().await?;
+    path.push(AUDIT_FILE);
+    Ok(path)
+}
+
+/// Appends a record to the audit log. The log is only ever appended to,
+/// and every record is written with a single write.
+pub async fn append(configured: Option<&PathBuf>, record: &AuditRecord) -> Result<()> {
+    let path = get_log_path(configured).await?;
+    let mut line = serde_json::to_string(record).context("Failed to serialize audit record")?;
+    line.push('\n');
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .await
+        .with_context(|| format!("Failed to open audit log `{}`", path.display()))?;
+
+    // Tokio only finishes pending writes once flushed
+    async {
+        file.write_all(line.as_bytes()).await?;
+        file.flush().await
+    }
+    .await
+    .with_context(|| format!("Failed to write audit log `{}`", path.display()))?;
+
+    Ok(())
+}
+
+/// Reads all records from the audit log, oldest first. The end of a run
+/// replaces its start, and lines that can't be parsed are skipped with a
+/// warning.
+pub async fn read(configured: Option<&PathBuf>) -> Result<Vec<AuditRecord>> {
+    let path = get_log_path(configured).await?;
+    if !path.is_file() {
+        return Ok(Vec::new());
+    }
+
+    let contents = fs::read_to_string(&path)
+        .await
+        .with_context(|| format!("Failed to read audit log `{}`", path.display()))?;
+
+    Ok(parse_records(&contents))
+}
+
+fn parse_records(contents: &str) -> Vec<AuditRecord> {
+    let mut records: Vec<AuditRecord> = Vec::new();
+    let mut positions = HashMap::new();
+    for (i, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let record: AuditRecord = match serde_json::from_str(line) {
+            Ok(record) => record,
+            Err(err) => {
+                report::warn(format!(
+                    "Skipping invalid audit record on line {}: {}",
+                    i + 1,
+                    err
+                ));
+                continue;
+            }
+        };
+
+        if record.id.is_empty() {
+            records.push(record);
+        } else if let Some(&pos) = positions.get(&record.id) {
+            records[pos] = record;
+        } else {
+            positions.insert(record.id.clone(), records.len());
+            records.push(record);
+        }
+    }
+
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(id: &str, finished: bool, exit_code: Option<i32>) -> String {
+        let mut value = serde_json::json!({
+            "timestamp": "2021-01-01T00:00:00Z",
+            "action": "run",
+            "user": "user",
+            "host": "host",
+            "repo": "repo",
+            "ref": "main",
+            "script": "hello.bash",
+            "commit": "abc",
+            "sha256": "def",
+            "args": [],
+            "exit_code": exit_code,
+            "duration_ms": 0,
+        });
+
+        if !id.is_empty() {
+            value["id"] = id.into();
+            value["finished"] = finished.into();
+        }
+
+        value.to_string()
+    }
+
+    #[test]
+    fn end_of_run_replaces_its_start() {
+        let contents = [
+            line("1", false, None),
+            line("2", false, None),
+            line("1", true, Some(3)),
+        ]
+        .join("\n");
+
+        let records = parse_records(&contents);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].id, "1");
+        assert!(records[0].finished);
+        assert_eq!(records[0].exit_code, Some(3));
+        assert_eq!(records[1].id, "2");
+        assert!(!records[1].finished);
+    }
+
+    #[test]
+    fn old_records_are_finished() {
+        let contents = [line("", false, Some(0)), line("", false, Some(1))].join("\n");
+
+        let records = parse_records(&contents);
+        assert_eq!(records.len(), 2);
+        assert!(records.iter().all(|record| record.finished));
+    }
+}
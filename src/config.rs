@@ -1,4 +1,10 @@
-use crate::repo::Repo;
+use crate::{
+    error::RemError,
+    http::{HttpClient, HttpConfig},
+    policy::Policy,
+    repo::{CachePolicy, Repo, RepoLocation},
+    sandbox::{SandboxProfile, DEFAULT_PROFILE},
+};
 use anyhow::{anyhow, Context, Result};
 use serde::{Deserialize, Serialize};
 use std::{collections::BTreeMap as Map, path::PathBuf};
@@ -9,30 +15,181 @@ pub struct Config {
     pub require_bash_extension: Option<String>,
     pub require_lib_extension: Option<String>,
 
+    /// Seconds after which cached mutable refs are checked against the remote.
+    /// Cached refs are reused indefinitely if this isn't set.
+    pub ref_ttl: Option<u64>,
+
+    /// File that executed and imported scripts are recorded in. Defaults to
+    /// `audit.jsonl` in the data dir.
+    pub audit_log: Option<PathBuf>,
+
+    /// Proxy, CA and timeout settings for requests to the provider APIs
+    #[serde(default, skip_serializing_if = "HttpConfig::is_default")]
+    pub http: HttpConfig,
+
+    /// Built from `http` when the config is loaded, and shared by all repos
+    #[serde(skip)]
+    pub http_client: HttpClient,
+
+    /// Named sandbox profiles, which can be used with `--sandbox-profile`
+    /// or set as a repo's default
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub sandbox: Map<String, SandboxProfile>,
+
+    /// Restricts which repos and refs scripts may be loaded from
+    #[serde(default, skip_serializing_if = "Policy::is_empty")]
+    pub policy: Policy,
+
+    /// Loaded from `/etc/rem/policy.toml` and enforced on top of `policy`.
+    /// It's never written to the user's config.
+    #[serde(skip)]
+    pub system_policy: Option<Policy>,
+
     #[serde(default)]
     pub repo: Map<String, Box<dyn Repo>>,
 }
 
+impl Config {
+    /// Determines how cached checkouts should be treated for the given repo
+    pub fn cache_policy(&self, repo: &dyn Repo, fresh: bool) -> CachePolicy {
+        match (fresh, repo.options().ref_ttl.or(self.ref_ttl)) {
+            (true, _) => CachePolicy::Fresh,
+            (false, Some(ttl)) => CachePolicy::Ttl(ttl),
+            (false, None) => CachePolicy::Cached,
+        }
+    }
+
+    /// Looks up a sandbox profile by name. The default profile can be
+    /// overridden in the config, but doesn't need to be defined.
+    pub fn sandbox_profile(&self, name: &str) -> Result<SandboxProfile> {
+        match self.sandbox.get(name) {
+            Some(profile) => Ok(profile.clone()),
+            None if name == DEFAULT_PROFILE => Ok(SandboxProfile::default()),
+            None => {
+                Err(RemError::NotFound(format!("Sandbox profile `{}` was not found", name)).into())
+            }
+        }
+    }
+
+    /// Makes sure the system policy and the user's policy allow loading
+    /// scripts from the given source
+    pub fn check_policy(
+        &self,
+        alias: Option<&str>,
+        location: &RepoLocation,
+        repo_ref: &str,
+    ) -> Result<()> {
+        if let Some(system_policy) = &self.system_policy {
+            system_policy.check("system policy", alias, location, repo_ref)?;
+        }
+
+        self.policy.check("policy", alias, location, repo_ref)
+    }
+
+    /// Finds the ref to list a saved repo's scripts at: `HEAD` if the
+    /// policies allow it, otherwise the first ref from `Repo::list_refs`
+    /// they allow. Returns `None` if the repo is blocked entirely, without
+    /// making any requests for it.
+    pub async fn allowed_ref(&self, alias: &str, repo: &dyn Repo) -> Result<Option<String>> {
+        let location = repo.location();
+        let policies = self
+            .system_policy
+            .iter()
+            .map(|policy| ("system policy", policy))
+            .chain([("policy", &self.policy)]);
+
+        for (source, policy) in policies {
+            if policy.check_repo(source, Some(alias), &location).is_err() {
+                return Ok(None);
+            }
+        }
+
+        let allowed = |repo_ref: &str| self.check_policy(Some(alias), &location, repo_ref).is_ok();
+        if allowed("HEAD") {
+            return Ok(Some("HEAD".to_owned()));
+        }
+
+        let cache = self.cache_policy(repo, false);
+        let refs = repo.list_refs(cache).await?;
+        Ok(refs.into_iter().find(|repo_ref| allowed(repo_ref)))
+    }
+
+    /// Checks whether a file name matches any of the configured script
+    /// extensions. Every file counts as a script if none are configured.
+    pub fn is_script_name(&self, name: &str) -> bool {
+        let extensions = [&self.require_bash_extension, &self.require_lib_extension];
+        if extensions.iter().all(|ext| ext.is_none()) {
+            return true;
+        }
+
+        extensions
+            .iter()
+            .filter_map(|ext| ext.as_deref())
+            .any(|ext| name.ends_with(ext))
+    }
+}
+
 fn get_config_path() -> Result<PathBuf> {
     let mut path = dirs::home_dir().ok_or(anyhow!("Failed to get home directory"))?;
     path.push(".remconf.toml");
     Ok(path)
 }
 
+/// Returns the directory rem caches repositories and indexes in,
+/// creating it if it doesn't exist yet
+pub async fn get_cache_dir() -> Result<PathBuf> {
+    let mut cache_dir = dirs::cache_dir().ok_or(anyhow!("Failed to get cache dir"))?;
+    cache_dir.push("rem");
+    if !cache_dir.is_dir() {
+        fs::create_dir(&cache_dir)
+            .await
+            .context("Failed to create cache dir")?;
+    }
+
+    Ok(cache_dir)
+}
+
+/// Returns the directory rem keeps persistent state in, like the trust
+/// store, creating it if it doesn't exist yet
+pub async fn get_data_dir() -> Result<PathBuf> {
+    let mut data_dir = dirs::data_dir().ok_or(anyhow!("Failed to get data dir"))?;
+    data_dir.push("rem");
+    if !data_dir.is_dir() {
+        fs::create_dir_all(&data_dir)
+            .await
+            .context("Failed to create data dir")?;
+    }
+
+    Ok(data_dir)
+}
+
 pub async fn load_config() -> Result<Config> {
     let path = get_config_path()?;
 
-    if !path.is_file() {
-        return Ok(Config::default());
+    let mut config = if path.is_file() {
+        let config_str = fs::read_to_string(&path).await?;
+        toml::from_str(&config_str).map_err(|err| {
+            RemError::ParseError(format!("Failed to parse `{}`: {}", path.display(), err))
+        })?
+    } else {
+        Config::default()
+    };
+
+    config.system_policy = Policy::load_system().await?;
+    config.http_client = HttpClient::new(&config.http)?;
+    for repo in config.repo.values_mut() {
+        repo.set_http_client(config.http_client.clone());
     }
 
-    let config_str = fs::read_to_string(&path).await?;
-    Ok(toml::from_str(&config_str)?)
+    Ok(config)
 }
 
 pub async fn save_config(config: &Config) -> Result<()> {
     let path = get_config_path()?;
-    let config_str = toml::to_string(config).context("Failed to serialize config")?;
+    // Going through a `Value` first makes sure tables are emitted after plain
+    // values, since flattened repo options would otherwise follow auth tables
+    let config_value = toml::Value::try_from(config).context("Failed to serialize config")?;
+    let config_str = toml::to_string(&config_value).context("Failed to serialize config")?;
     fs::write(path, &config_str).await?;
 
     Ok(())
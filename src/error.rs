@@ -0,0 +1,165 @@
+use std::{error::Error, fmt};
+
+/// Errors that callers like CI wrappers may want to tell apart. Each kind
+/// exits with its own code, based on `sysexits.h`, and all other errors
+/// exit with 1.
+#[derive(Debug)]
+pub enum RemError {
+    /// A script, repo or ref doesn't exist
+    NotFound(String),
+    /// Credentials are missing, invalid or don't grant access
+    Unauthorized(String),
+    /// An API rate limit was exceeded and didn't reset in time
+    RateLimited(String),
+    /// A request failed, even after retrying it
+    Network(String),
+    /// A script source, config or policy couldn't be parsed
+    ParseError(String),
+    /// A policy blocked the script
+    PolicyViolation(String),
+    /// The script wasn't reviewed, or the review was declined
+    Untrusted(String),
+    /// The script was stopped because it ran longer than its timeout
+    TimedOut(String),
+    /// The script ran, but exited with the given code. Scripts killed by a
+    /// signal get `128 + signal`, like in the shell.
+    ScriptFailed(i32),
+}
+
+/// Exit code of failed scripts whose own code is reserved for rem's errors
+pub const SCRIPT_FAILED_RESERVED: i32 = 79;
+
+/// Whether rem uses the exit code for its own errors, i.e. the `sysexits.h`
+/// range and timeouts
+fn is_reserved(code: i32) -> bool {
+    (64..=78).contains(&code) || code == 124
+}
+
+impl RemError {
+    /// Code rem exits with. Failed scripts exit with their own code, unless
+    /// rem reserves it, then they exit with `SCRIPT_FAILED_RESERVED`.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            RemError::ScriptFailed(code) if is_reserved(*code) => SCRIPT_FAILED_RESERVED,
+            RemError::ScriptFailed(code) => *code,
+            // EX_DATAERR
+            RemError::ParseError(_) => 65,
+            // EX_NOINPUT
+            RemError::NotFound(_) => 66,
+            // EX_NOUSER
+            RemError::Unauthorized(_) => 67,
+            // EX_UNAVAILABLE
+            RemError::Network(_) => 69,
+            // EX_TEMPFAIL
+            RemError::RateLimited(_) => 75,
+            // EX_NOPERM
+            RemError::PolicyViolation(_) => 77,
+            // EX_CONFIG, the script isn't set up to be run yet
+            RemError::Untrusted(_) => 78,
+            // Like timeout(1)
+            RemError::TimedOut(_) => 124,
+        }
+    }
+
+    /// The code a failed script exited with, before it was remapped
+    pub fn script_exit_code(&self) -> Option<i32> {
+        match self {
+            RemError::ScriptFailed(code) => Some(*code),
+            _ => None,
+        }
+    }
+
+    /// Name of the error kind, used in JSON output
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RemError::NotFound(_) => "not_found",
+            RemError::Unauthorized(_) => "unauthorized",
+            RemError::RateLimited(_) => "rate_limited",
+            RemError::Network(_) => "network",
+            RemError::ParseError(_) => "parse_error",
+            RemError::PolicyViolation(_) => "policy_violation",
+            RemError::Untrusted(_) => "untrusted",
+            RemError::TimedOut(_) => "timed_out",
+            RemError::ScriptFailed(_) => "script_failed",
+        }
+    }
+
+    /// Creates an error from its kind's name, e.g. as reported by a
+    /// plugin. Returns `None` for unknown kinds.
+    pub fn from_kind(kind: &str, message: String) -> Option<Self> {
+        match kind {
+            "not_found" => Some(RemError::NotFound(message)),
+            "unauthorized" => Some(RemError::Unauthorized(message)),
+            "rate_limited" => Some(RemError::RateLimited(message)),
+            "network" => Some(RemError::Network(message)),
+            "parse_error" => Some(RemError::ParseError(message)),
+            "policy_violation" => Some(RemError::PolicyViolation(message)),
+            _ => None,
+        }
+    }
+
+    /// Suggests how to fix the error, shown below it
+    pub fn hint(&self) -> Option<&'static str> {
+        match self {
+            RemError::NotFound(_) => Some(
+                "Check the repo, path and ref with `rem repo ls` and `rem ls`. Private repos also look like this without valid credentials.",
+            ),
+            RemError::Unauthorized(_) => Some(
+                "Check the repo's token and its permissions, or save it again with `rem repo add`",
+            ),
+            RemError::RateLimited(_) => {
+                Some("Saving the repo with a token raises the limit, see `rem repo add --help`")
+            }
+            RemError::Network(_) => {
+                Some("Check your connection and the `[http]` settings in `~/.remconf.toml`")
+            }
+            RemError::PolicyViolation(_) => Some(
+                "Policies are set in the `[policy]` section of `~/.remconf.toml` and in `/etc/rem/policy.toml`",
+            ),
+            RemError::Untrusted(_) => Some(
+                "Scripts are reviewed when they're run in a terminal, or can be accepted with `--yes`",
+            ),
+            RemError::TimedOut(_) => Some("Pass a longer `--timeout` if the script needs more time"),
+            RemError::ParseError(_) | RemError::ScriptFailed(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for RemError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RemError::NotFound(message)
+            | RemError::Unauthorized(message)
+            | RemError::RateLimited(message)
+            | RemError::Network(message)
+            | RemError::ParseError(message)
+            | RemError::PolicyViolation(message)
+            | RemError::Untrusted(message)
+            | RemError::TimedOut(message) => write!(f, "{}", message),
+            RemError::ScriptFailed(code) => write!(f, "Script exited with code {}", code),
+        }
+    }
+}
+
+impl Error for RemError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remaps_reserved_script_exit_codes() {
+        assert_eq!(RemError::ScriptFailed(2).exit_code(), 2);
+        assert_eq!(RemError::ScriptFailed(130).exit_code(), 130);
+        assert_eq!(
+            RemError::ScriptFailed(66).exit_code(),
+            SCRIPT_FAILED_RESERVED
+        );
+        assert_eq!(
+            RemError::ScriptFailed(124).exit_code(),
+            SCRIPT_FAILED_RESERVED
+        );
+        assert_eq!(RemError::ScriptFailed(66).script_exit_code(), Some(66));
+        assert_eq!(RemError::NotFound(String::new()).script_exit_code(), None);
+    }
+}
@@ -1,14 +1,22 @@
-use crate::{repo::Repo, ScriptSource};
+use crate::{
+    repo::{CachePolicy, Repo, RepoEntry, RepoLocation, RepoOptions},
+    ScriptSource,
+};
 use anyhow::Result;
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use url::Url;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct GitRepo {
     url: String,
+
+    #[serde(flatten)]
+    options: RepoOptions,
 }
 
-pub const PROVIDER: &'static str = "git";
+pub const PROVIDER: &str = "git";
 
 #[async_trait]
 #[typetag::serde]
@@ -18,15 +26,73 @@ impl Repo for GitRepo {
     }
 
     fn readable(&self) -> String {
-        format!("{}", self.url)
+        self.url.to_string()
+    }
+
+    fn location(&self) -> RepoLocation {
+        let (host, path) = match Url::parse(&self.url) {
+            Ok(url) => (
+                url.host_str().unwrap_or_default().to_owned(),
+                url.path().to_owned(),
+            ),
+            // scp-like urls (`git@host:path`) can't be parsed as URLs
+            Err(_) => match self.url.split_once(':') {
+                Some((host, path)) => (
+                    host.rsplit('@').next().unwrap_or(host).to_owned(),
+                    path.to_owned(),
+                ),
+                None => (String::new(), self.url.clone()),
+            },
+        };
+
+        let path = path.trim_matches('/');
+        RepoLocation {
+            host,
+            path: path.strip_suffix(".git").unwrap_or(path).to_owned(),
+        }
+    }
+
+    fn options(&self) -> &RepoOptions {
+        &self.options
     }
 
     fn box_clone(&self) -> Box<dyn Repo> {
         Box::new(self.clone())
     }
 
-    async fn fetch_script(&self, path: &str, rref: &str, fresh: bool) -> Result<String> {
-        Ok(cmd::fetch_script(&self.url, rref, path, fresh).await?)
+    async fn fetch_bytes(&self, path: &str, rref: &str, cache: CachePolicy) -> Result<Vec<u8>> {
+        Ok(cmd::fetch_bytes(&self.url, rref, path, cache).await?)
+    }
+
+    async fn list_scripts(
+        &self,
+        dir: &str,
+        rref: &str,
+        recursive: bool,
+        cache: CachePolicy,
+    ) -> Result<Vec<RepoEntry>> {
+        cmd::list_files(&self.url, rref, dir, recursive, cache).await
+    }
+
+    async fn resolve_commit(&self, rref: &str, cache: CachePolicy) -> Result<String> {
+        cmd::resolve_commit(&self.url, rref, cache).await
+    }
+
+    async fn list_refs(&self, _cache: CachePolicy) -> Result<Vec<String>> {
+        cmd::list_refs(&self.url).await
+    }
+
+    async fn fetch_at_commit(
+        &self,
+        path: &str,
+        rref: &str,
+        cache: CachePolicy,
+    ) -> Result<(Vec<u8>, String)> {
+        cmd::fetch_at_commit(&self.url, rref, path, cache).await
+    }
+
+    async fn checkout(&self, rref: &str, cache: CachePolicy) -> Result<Option<PathBuf>> {
+        Ok(Some(cmd::checkout_ref(&self.url, rref, cache).await?))
     }
 }
 
@@ -34,26 +100,58 @@ impl GitRepo {
     pub fn from_src(src: &ScriptSource) -> Box<dyn Repo> {
         Box::new(Self {
             url: src.repo.clone(),
+            options: RepoOptions::default(),
         })
     }
 }
 
 mod cmd {
-    use anyhow::{anyhow, bail, Context, Result};
+    use crate::{
+        config::get_cache_dir,
+        error::RemError,
+        repo::{order_refs, CachePolicy, RepoEntry},
+        report,
+    };
+    use anyhow::{Context, Result};
     use async_process::{Command, Stdio};
+    use lazy_static::lazy_static;
+    use regex::Regex;
     use sanitize_filename::{sanitize_with_options, Options as SanitizeOptions};
-    use std::path::{Path, PathBuf};
+    use serde::{Deserialize, Serialize};
+    use std::{
+        io,
+        path::{Path, PathBuf},
+        sync::atomic::{AtomicUsize, Ordering},
+        time::{SystemTime, UNIX_EPOCH},
+    };
     use tokio::fs;
 
+    lazy_static! {
+        /// Full or abbreviated commit SHAs, as `git` accepts them
+        static ref COMMIT_SHA_REGEX: Regex = Regex::new(r"^[0-9a-f]{4,64}$").unwrap();
+    }
+
+    /// Bookkeeping for a cached ref, stored inside the `.git` dir of the
+    /// checkout so it doesn't show up in `git diff`
+    #[derive(Serialize, Deserialize)]
+    struct RefMeta {
+        fetched_at: u64,
+        commit: String,
+        immutable: bool,
+    }
+
+    const REF_META_FILE: &str = ".git/rem-ref.json";
+
     async fn get_ref_dir(repo: &str, rref: &str) -> Result<PathBuf> {
-        let mut cache_dir = dirs::cache_dir().ok_or(anyhow!("Failed to get cache dir"))?;
-        cache_dir.push("rem");
-        if !cache_dir.is_dir() {
-            fs::create_dir(&cache_dir)
-                .await
-                .context("Failed to create cache dir")?;
-        }
+        let mut cache_dir = get_cache_dir().await?;
+        cache_dir.push(ref_dir_name(repo, rref));
+        Ok(cache_dir)
+    }
 
+    /// Name of the cache directory for a ref, always a single path
+    /// component. Refs are percent-encoded, so `feature/login` doesn't end
+    /// up inside the checkout of `feature`, and no ref can leave the cache.
+    pub(super) fn ref_dir_name(repo: &str, rref: &str) -> String {
         let mut sanitized_path = sanitize_with_options(
             repo,
             SanitizeOptions {
@@ -63,73 +161,437 @@ mod cmd {
             },
         );
 
-        sanitized_path.push_str("@");
+        sanitized_path.push('@');
         let mut repo_path = sanitized_path.replace('@', ":");
-        repo_path.push_str(&rref);
+        for byte in rref.bytes() {
+            match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'.' | b'-' | b'_' => {
+                    repo_path.push(byte as char)
+                }
+                _ => repo_path.push_str(&format!("%{:02X}", byte)),
+            }
+        }
 
-        cache_dir.push(repo_path);
-        Ok(PathBuf::from(cache_dir))
+        repo_path
     }
 
-    async fn run_git_command(dir: &Path, args: &[&str]) -> Result<()> {
-        let mut child = Command::new("git")
+    async fn run_git_command(dir: &Path, args: &[&str]) -> Result<String> {
+        let output = Command::new("git")
             .current_dir(dir)
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .args(args)
-            .spawn()?;
-
-        let status = child.status().await?;
-        let output = child.output().await?;
+            .output()
+            .await?;
 
-        if status.success() {
-            Ok(())
+        if output.status.success() {
+            Ok(String::from_utf8_lossy(&output.stdout).into_owned())
         } else {
             let stderr = String::from_utf8_lossy(&output.stderr);
-            let trimmed = stderr.trim_end();
-            bail!("git: {}", trimmed);
+            Err(git_error(stderr.trim_end()).into())
         }
     }
 
-    pub async fn clone_ref(repo: &str, rref: &str, ref_path: &Path) -> Result<()> {
-        run_git_command(&ref_path, &["init"]).await?;
-        run_git_command(&ref_path, &["remote", "add", "origin", repo]).await?;
-        run_git_command(&ref_path, &["fetch", "--depth", "1", "origin", rref]).await?;
-        run_git_command(&ref_path, &["checkout", "FETCH_HEAD"]).await?;
+    /// Classifies a failed git command by its message. Anything that isn't
+    /// a missing repo or ref, or denied access, is treated as the remote
+    /// being unreachable.
+    pub(super) fn git_error(stderr: &str) -> RemError {
+        const NOT_FOUND: &[&str] = &[
+            "couldn't find remote ref",
+            "repository not found",
+            "does not appear to be a git repository",
+            "not found",
+            "unknown revision",
+            "not a valid object name",
+            "unadvertised object",
+            "returned error: 404",
+        ];
+        const UNAUTHORIZED: &[&str] = &[
+            "permission denied",
+            "authentication failed",
+            "could not read username",
+            "returned error: 401",
+            "returned error: 403",
+        ];
+
+        let message = format!("git: {}", stderr);
+        let lowercase = stderr.to_lowercase();
+        let contains_any = |patterns: &[&str]| patterns.iter().any(|p| lowercase.contains(p));
+
+        if contains_any(NOT_FOUND) {
+            RemError::NotFound(message)
+        } else if contains_any(UNAUTHORIZED) {
+            RemError::Unauthorized(message)
+        } else {
+            RemError::Network(message)
+        }
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+
+    async fn read_ref_meta(ref_path: &Path) -> Option<RefMeta> {
+        let contents = fs::read_to_string(ref_path.join(REF_META_FILE))
+            .await
+            .ok()?;
+        serde_json::from_str(&contents).ok()
+    }
+
+    async fn write_ref_meta(ref_path: &Path, meta: &RefMeta) -> Result<()> {
+        let contents = serde_json::to_string(meta)?;
+        fs::write(ref_path.join(REF_META_FILE), contents)
+            .await
+            .context("Failed to write ref metadata")?;
 
         Ok(())
     }
 
-    pub async fn fetch_script(
+    /// Tags and commit SHAs never move, so they never need to be checked
+    /// against the remote again. Refs that look like a SHA only count if
+    /// they resolved to that commit and weren't a branch.
+    pub(super) async fn is_immutable_ref(ref_path: &Path, rref: &str, commit: &str) -> bool {
+        // FETCH_HEAD lines look like `<sha>\t\ttag 'v1.0' of <url>`
+        let fetch_head = fs::read_to_string(ref_path.join(".git/FETCH_HEAD"))
+            .await
+            .unwrap_or_default();
+
+        if COMMIT_SHA_REGEX.is_match(rref) && commit.starts_with(rref) {
+            return !fetch_head.contains(&format!("\tbranch '{}' of", rref));
+        }
+
+        fetch_head.contains(&format!("\ttag '{}' of", rref))
+    }
+
+    /// Resolves the commit a ref currently points to on the remote
+    async fn remote_commit(ref_path: &Path, rref: &str) -> Result<Option<String>> {
+        let output = run_git_command(ref_path, &["ls-remote", "origin", rref]).await?;
+        let branch_ref = format!("refs/heads/{}", rref);
+
+        let refs = output
+            .lines()
+            .filter_map(|line| line.split_once('\t'))
+            .collect::<Vec<_>>();
+
+        let exact = refs
+            .iter()
+            .find(|(_, name)| *name == rref || *name == branch_ref);
+
+        Ok(exact
+            .or_else(|| refs.first())
+            .map(|(sha, _)| sha.to_string()))
+    }
+
+    pub async fn clone_ref(repo: &str, rref: &str, ref_path: &Path) -> Result<()> {
+        run_git_command(ref_path, &["init"]).await?;
+        run_git_command(ref_path, &["remote", "add", "origin", repo]).await?;
+
+        let fetch = &["fetch", "--depth", "1", "origin", rref];
+        match run_git_command(ref_path, fetch).await {
+            Ok(_) => {
+                run_git_command(ref_path, &["checkout", "FETCH_HEAD"]).await?;
+            }
+            // Servers only hand out commits by name or full SHA, if at all,
+            // so other commits are looked up in the whole history
+            Err(err) if COMMIT_SHA_REGEX.is_match(rref) => {
+                run_git_command(ref_path, &["fetch", "--tags", "origin"]).await?;
+
+                let commit = format!("{}^{{commit}}", rref);
+                let resolved = run_git_command(ref_path, &["rev-parse", "--verify", &commit])
+                    .await
+                    .map_err(|_| err)?;
+                run_git_command(ref_path, &["checkout", resolved.trim()]).await?;
+            }
+            Err(err) => return Err(err),
+        }
+
+        let commit = run_git_command(ref_path, &["rev-parse", "HEAD"]).await?;
+        let commit = commit.trim().to_owned();
+        let meta = RefMeta {
+            fetched_at: now(),
+            immutable: is_immutable_ref(ref_path, rref, &commit).await,
+            commit,
+        };
+
+        write_ref_meta(ref_path, &meta).await
+    }
+
+    /// Checks whether a cached checkout is still up to date with the remote,
+    /// refreshing its timestamp if it is. If the remote can't be reached,
+    /// the cached checkout is used anyway.
+    async fn is_up_to_date(ref_path: &Path, rref: &str, ttl: u64) -> Result<bool> {
+        let mut meta = match read_ref_meta(ref_path).await {
+            Some(meta) => meta,
+            None => return Ok(false),
+        };
+
+        if meta.immutable || now().saturating_sub(meta.fetched_at) < ttl {
+            return Ok(true);
+        }
+
+        // The timestamp is left alone if the remote can't be reached, so
+        // it's checked again on the next run
+        match remote_commit(ref_path, rref).await {
+            Ok(Some(commit)) if commit == meta.commit => {
+                meta.fetched_at = now();
+                write_ref_meta(ref_path, &meta).await?;
+                Ok(true)
+            }
+            Ok(_) => Ok(false),
+            Err(err) => {
+                report::warn(format!(
+                    "Failed to check `{}` for updates, using the cached checkout: {:#}",
+                    rref, err
+                ));
+                Ok(true)
+            }
+        }
+    }
+
+    /// Makes sure an up to date checkout of the ref exists in the cache
+    /// and returns its path. New checkouts are cloned next to the cached
+    /// one and only replace it once they're complete, so a failed clone,
+    /// e.g. while offline, keeps the cached checkout.
+    pub async fn checkout_ref(repo: &str, rref: &str, cache: CachePolicy) -> Result<PathBuf> {
+        let ref_path = get_ref_dir(repo, rref).await?;
+
+        let mut is_clean = cache != CachePolicy::Fresh && ref_path.is_dir() && {
+            let is_clean = run_git_command(&ref_path, &["diff", "--quiet"]);
+            is_clean.await.is_ok()
+        };
+
+        if let (true, CachePolicy::Ttl(ttl)) = (is_clean, cache) {
+            is_clean = is_up_to_date(&ref_path, rref, ttl).await?;
+        }
+
+        if !is_clean {
+            let temp_path = sibling_path(&ref_path, "new");
+            fs::create_dir_all(&temp_path)
+                .await
+                .context("Failed to create ref dir")?;
+
+            if let Err(err) = clone_ref(repo, rref, &temp_path).await {
+                let _ = fs::remove_dir_all(&temp_path).await;
+                return Err(err);
+            }
+
+            replace_dir(&temp_path, &ref_path).await?;
+        }
+
+        Ok(ref_path)
+    }
+
+    /// A unique path next to `path`. Refs are percent-encoded in cache dir
+    /// names, so the `~` can't clash with any of them.
+    fn sibling_path(path: &Path, kind: &str) -> PathBuf {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+        let mut name = path.file_name().unwrap_or_default().to_owned();
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        name.push(format!("~{}-{}-{}", kind, std::process::id(), id));
+        path.with_file_name(name)
+    }
+
+    /// Moves the `new` directory to `target`, replacing what's there.
+    /// Another run may put its own checkout in place in between, which is
+    /// then kept instead.
+    async fn replace_dir(new: &Path, target: &Path) -> Result<()> {
+        let old = sibling_path(target, "old");
+        let had_old = fs::rename(target, &old).await.is_ok();
+
+        let moved = fs::rename(new, target).await;
+        if moved.is_err() {
+            let _ = fs::remove_dir_all(new).await;
+        }
+
+        let replaced = moved.is_ok() || target.is_dir();
+        match (had_old, replaced) {
+            (true, true) => fs::remove_dir_all(&old).await?,
+            (true, false) => fs::rename(&old, target).await?,
+            (false, _) => {}
+        }
+
+        match replaced {
+            true => Ok(()),
+            false => Ok(moved.context("Failed to move checkout into the cache")?),
+        }
+    }
+
+    pub async fn fetch_bytes(
         repo: &str,
         rref: &str,
         path: &str,
-        force_fresh: bool,
-    ) -> Result<String> {
-        let mut ref_path = get_ref_dir(repo, rref).await?;
-        if force_fresh && ref_path.is_dir() {
-            fs::remove_dir_all(&ref_path).await?;
-        }
+        cache: CachePolicy,
+    ) -> Result<Vec<u8>> {
+        let ref_path = checkout_ref(repo, rref, cache).await?;
+        read_file(repo, rref, &ref_path, path).await
+    }
 
-        {
-            let is_clean = run_git_command(&ref_path, &["diff", "--quiet"]);
-            let is_clean = ref_path.is_dir() && is_clean.await.is_ok();
+    /// Reads the file and the commit from the same checkout
+    pub async fn fetch_at_commit(
+        repo: &str,
+        rref: &str,
+        path: &str,
+        cache: CachePolicy,
+    ) -> Result<(Vec<u8>, String)> {
+        let ref_path = checkout_ref(repo, rref, cache).await?;
+        let contents = read_file(repo, rref, &ref_path, path).await?;
+        let commit = run_git_command(&ref_path, &["rev-parse", "HEAD"]).await?;
+        Ok((contents, commit.trim().to_owned()))
+    }
 
-            if !is_clean {
-                fs::create_dir_all(&ref_path)
-                    .await
-                    .context("Failed to create ref dir")?;
+    async fn read_file(repo: &str, rref: &str, ref_path: &Path, path: &str) -> Result<Vec<u8>> {
+        match fs::read(ref_path.join(path)).await {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let message = format!("Script `{}` was not found in `{}@{}`", path, repo, rref);
+                Err(RemError::NotFound(message).into())
+            }
+            result => Ok(result?),
+        }
+    }
+
+    pub async fn resolve_commit(repo: &str, rref: &str, cache: CachePolicy) -> Result<String> {
+        let ref_path = checkout_ref(repo, rref, cache).await?;
+        let commit = run_git_command(&ref_path, &["rev-parse", "HEAD"]).await?;
+        Ok(commit.trim().to_owned())
+    }
 
-                let clone_result = clone_ref(repo, rref, &ref_path).await;
+    /// Asks the remote for its tags and branches
+    pub async fn list_refs(repo: &str) -> Result<Vec<String>> {
+        let cache_dir = get_cache_dir().await?;
+        let output = run_git_command(&cache_dir, &["ls-remote", "--tags", "--heads", repo]).await?;
 
-                if !clone_result.is_ok() {
-                    fs::remove_dir_all(&ref_path).await?;
-                    clone_result?;
+        // Lines look like `<sha>\trefs/tags/v1.0`, annotated tags are listed
+        // a second time as `refs/tags/v1.0^{}`
+        let mut tags = Vec::new();
+        let mut branches = Vec::new();
+        for (_, name) in output.lines().filter_map(|line| line.split_once('\t')) {
+            if let Some(tag) = name.strip_prefix("refs/tags/") {
+                if !tag.ends_with("^{}") {
+                    tags.push(tag.to_owned());
                 }
+            } else if let Some(branch) = name.strip_prefix("refs/heads/") {
+                branches.push(branch.to_owned());
             }
         }
 
-        ref_path.push(path);
-        Ok(fs::read_to_string(&ref_path).await?)
+        Ok(order_refs(tags, branches))
+    }
+
+    pub async fn list_files(
+        repo: &str,
+        rref: &str,
+        dir: &str,
+        recursive: bool,
+        cache: CachePolicy,
+    ) -> Result<Vec<RepoEntry>> {
+        let ref_path = checkout_ref(repo, rref, cache).await?;
+
+        let mut args = vec!["ls-tree"];
+        if recursive {
+            args.push("-r");
+        }
+
+        // A trailing slash makes ls-tree list the contents of the directory
+        // instead of the directory itself
+        let tree_path = format!("{}/", dir);
+        args.push("HEAD");
+        if !dir.is_empty() {
+            args.extend_from_slice(&["--", &tree_path]);
+        }
+
+        // Lines look like `<mode> <type> <object>\t<path>`
+        let output = run_git_command(&ref_path, &args).await?;
+        let entries = output
+            .lines()
+            .filter_map(|line| line.split_once('\t'))
+            .map(|(info, path)| RepoEntry {
+                path: path.to_owned(),
+                is_dir: info.split(' ').nth(1) == Some("tree"),
+            })
+            .collect();
+
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::cmd::{git_error, is_immutable_ref, ref_dir_name};
+    use crate::error::RemError;
+    use std::path::{Component, Path};
+
+    #[test]
+    fn ref_dirs_stay_inside_the_cache() {
+        for rref in ["/../../../home/victim", "..", "feature/login", "a/../../b"] {
+            let name = ref_dir_name("git@example.invalid:a/b", rref);
+            let components = Path::new(&name).components().collect::<Vec<_>>();
+            assert!(
+                matches!(components.as_slice(), [Component::Normal(_)]),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn slashed_refs_get_their_own_dir() {
+        let repo = "git@github.com:org/repo";
+        assert_eq!(ref_dir_name(repo, "main"), "git:github.com:org:repo:main");
+        assert_eq!(
+            ref_dir_name(repo, "feature/login"),
+            "git:github.com:org:repo:feature%2Flogin"
+        );
+    }
+
+    #[test]
+    fn classifies_git_errors() {
+        let missing_ref = "fatal: couldn't find remote ref nope";
+        assert!(matches!(git_error(missing_ref), RemError::NotFound(_)));
+
+        let missing_repo =
+            "ERROR: Repository not found.\nfatal: Could not read from remote repository.";
+        assert!(matches!(git_error(missing_repo), RemError::NotFound(_)));
+
+        let denied = "git@github.com: Permission denied (publickey).\nfatal: Could not read from remote repository.";
+        assert!(matches!(git_error(denied), RemError::Unauthorized(_)));
+
+        let offline = "fatal: unable to access 'https://example.com/a.git/': Could not resolve host: example.com";
+        assert!(matches!(git_error(offline), RemError::Network(_)));
+    }
+
+    #[tokio::test]
+    async fn tags_and_commits_are_immutable() {
+        const COMMIT: &str = "cafe1234e70ecc4275071f8ac21f05e342f203de";
+        let dir = std::env::temp_dir().join(format!("rem-git-test-{}", std::process::id()));
+        let fetch_head = dir.join(".git/FETCH_HEAD");
+        tokio::fs::create_dir_all(fetch_head.parent().unwrap())
+            .await
+            .unwrap();
+
+        let cases = [
+            ("v1.0", "\t\ttag 'v1.0' of fake.host:src", true),
+            ("main", "\t\tbranch 'main' of fake.host:src", false),
+            ("cafe1234", "\t\tfake.host:src", true),
+            (COMMIT, "\t\tfake.host:src", true),
+            ("cafe", "\t\tbranch 'cafe' of fake.host:src", false),
+            ("beef", "\t\tfake.host:src", false),
+        ];
+
+        for (rref, line, expected) in cases {
+            let contents = format!("{}{}\n", COMMIT, line);
+            tokio::fs::write(&fetch_head, contents).await.unwrap();
+            assert_eq!(
+                is_immutable_ref(&dir, rref, COMMIT).await,
+                expected,
+                "{}",
+                rref
+            );
+        }
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
     }
 }
@@ -1,21 +1,110 @@
-use crate::{repo::Repo, Password};
-use anyhow::{bail, Result};
+use crate::{
+    http::{build_url, response_error, HttpClient},
+    repo::{order_refs, CachePolicy, Repo, RepoEntry, RepoLocation, RepoOptions},
+    Password,
+};
+use anyhow::{anyhow, bail, Context, Result};
 use async_trait::async_trait;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use reqwest::RequestBuilder;
 use serde::{Deserialize, Serialize};
-use std::env;
+use std::{
+    env,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt};
 use url::Url;
 
+const API_URL: &str = "https://api.github.com";
+
+/// Default media type of the API
+const JSON_MEDIA_TYPE: &str = "application/vnd.github.v3+json";
+
+/// Returns file contents as they are, instead of a JSON description
+const RAW_MEDIA_TYPE: &str = "application/vnd.github.raw";
+
+const APP_TOKEN_CACHE_DIR: &str = "github-app";
+
+/// GitHub rejects app JWTs that are valid for longer than 10 minutes
+const APP_JWT_LIFETIME: u64 = 9 * 60;
+
+/// Installation tokens are renewed this long before they expire, so they
+/// don't run out in the middle of a run
+const APP_TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Deserialize)]
+struct GithubCommitResponse {
+    sha: String,
+}
+
+/// A tag or branch, as listed by the API
+#[derive(Debug, Deserialize)]
+struct GithubRef {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct GithubContentsEntry {
+    path: String,
+    #[serde(rename = "type")]
+    entry_type: String,
+    sha: String,
+    /// Only set for symlinks
+    target: Option<String>,
+    /// Only set for submodules
+    submodule_git_url: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GithubInstallationResponse {
+    id: u64,
+}
+
+/// An installation access token, which is also kept in the cache dir
+/// until it expires
+#[derive(Debug, Serialize, Deserialize)]
+struct GithubInstallationToken {
+    token: String,
+    expires_at: String,
+}
+
+#[derive(Serialize)]
+struct GithubAppClaims {
+    iat: u64,
+    exp: u64,
+    iss: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct GithubTreeResponse {
+    tree: Vec<GithubTreeEntry>,
+    truncated: bool,
+}
+
 #[derive(Debug, Deserialize)]
-struct GithubFileResponse {
-    download_url: String,
+struct GithubTreeEntry {
+    path: String,
+    #[serde(rename = "type")]
+    entry_type: String,
 }
 
-pub const PROVIDER: &'static str = "github";
+pub const PROVIDER: &str = "github";
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct GithubRepo {
     project_id: String,
     auth: Option<GithubAuth>,
+
+    #[serde(flatten)]
+    options: RepoOptions,
+
+    #[serde(skip)]
+    http: HttpClient,
+
+    /// Only overridden to point to a mock server in tests
+    #[serde(skip)]
+    api_url: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -25,10 +114,39 @@ enum GithubPassword {
     FromEnv(String),
 }
 
+impl GithubPassword {
+    fn get(&self) -> Result<String> {
+        match self {
+            GithubPassword::Saved(saved) => Ok(saved.to_string()),
+            GithubPassword::FromEnv(var) => Ok(env::var(var)?),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
-struct GithubAuth {
-    username: String,
-    password: GithubPassword,
+#[serde(untagged)]
+enum GithubAuth {
+    /// Username and password or classic token, sent as basic auth
+    Basic {
+        username: String,
+        password: GithubPassword,
+    },
+    /// Token sent as bearer auth, like fine-grained tokens or the
+    /// `GITHUB_TOKEN` of an Actions workflow
+    Token { token: GithubPassword },
+    /// Installation of a GitHub App, which is exchanged for short-lived
+    /// installation tokens
+    App {
+        app_id: u64,
+        installation_id: u64,
+        private_key: PathBuf,
+    },
+}
+
+/// Credentials of a GitHub App, given when adding a repo
+pub struct GithubApp {
+    pub app_id: u64,
+    pub private_key: PathBuf,
 }
 
 #[async_trait]
@@ -42,56 +160,382 @@ impl Repo for GithubRepo {
         format!("github.com/{}", &self.project_id)
     }
 
+    fn location(&self) -> RepoLocation {
+        RepoLocation {
+            host: "github.com".to_owned(),
+            path: self.project_id.clone(),
+        }
+    }
+
+    fn options(&self) -> &RepoOptions {
+        &self.options
+    }
+
     fn box_clone(&self) -> Box<dyn Repo> {
         Box::new(self.clone())
     }
 
-    async fn fetch_script(&self, path: &str, repo_ref: &str, _fresh: bool) -> Result<String> {
-        let script_url = format!(
-            "https://api.github.com/repos/{}/contents/{}?ref={}",
-            self.project_id, path, repo_ref,
+    fn set_http_client(&mut self, client: HttpClient) {
+        self.http = client;
+    }
+
+    async fn fetch_bytes(
+        &self,
+        path: &str,
+        repo_ref: &str,
+        _cache: CachePolicy,
+    ) -> Result<Vec<u8>> {
+        let script_url = self.contents_url(path, repo_ref)?;
+        let req = self
+            .authorize(api_request(&self.http, script_url, RAW_MEDIA_TYPE))
+            .await?;
+        let resp = self.http.send_cached(req).await?;
+        if !resp.status().is_success() {
+            if resp.json().is_ok_and(|error| is_too_large(&error)) {
+                let sha = self.find_blob(path, repo_ref).await?;
+                return self.fetch_blob(&sha).await;
+            }
+
+            return Err(resp.error(PROVIDER));
+        }
+
+        // The raw media type only applies to files, everything else is
+        // still described as JSON
+        if !resp.is_json() {
+            return Ok(resp.into_bytes());
+        }
+
+        let entry = match resp.json::<serde_json::Value>()? {
+            serde_json::Value::Array(_) => bail!("`{}` is a directory", path),
+            entry => serde_json::from_value::<GithubContentsEntry>(entry)?,
+        };
+
+        // Symlinks to files in the repo are resolved by the API, so only
+        // ones pointing somewhere else end up here
+        match entry.entry_type.as_str() {
+            "file" => self.fetch_blob(&entry.sha).await,
+            "symlink" => bail!(
+                "`{}` is a symlink to `{}`, which is not a file in the repository",
+                path,
+                entry.target.unwrap_or_default()
+            ),
+            "submodule" => bail!(
+                "`{}` is a submodule of `{}`, scripts must be in the repository itself",
+                path,
+                entry.submodule_git_url.unwrap_or_default()
+            ),
+            other => bail!("`{}` has unsupported type `{}`", path, other),
+        }
+    }
+
+    async fn list_scripts(
+        &self,
+        dir: &str,
+        repo_ref: &str,
+        recursive: bool,
+        _cache: CachePolicy,
+    ) -> Result<Vec<RepoEntry>> {
+        if recursive {
+            return self.list_tree(dir, repo_ref).await;
+        }
+
+        let entries = self
+            .list_contents(dir, repo_ref)
+            .await?
+            .into_iter()
+            .filter(|entry| entry.entry_type == "file" || entry.entry_type == "dir")
+            .map(|entry| RepoEntry {
+                is_dir: entry.entry_type == "dir",
+                path: entry.path,
+            })
+            .collect();
+
+        Ok(entries)
+    }
+
+    async fn checkout(&self, _repo_ref: &str, _cache: CachePolicy) -> Result<Option<PathBuf>> {
+        Ok(None)
+    }
+
+    async fn resolve_commit(&self, repo_ref: &str, _cache: CachePolicy) -> Result<String> {
+        let commit_url = self.repo_url(&["commits", repo_ref], &[])?;
+        let req = self
+            .authorize(api_request(&self.http, commit_url, JSON_MEDIA_TYPE))
+            .await?;
+        let resp = self.http.send_cached(req).await?;
+        if !resp.status().is_success() {
+            return Err(resp.error(PROVIDER));
+        }
+
+        Ok(resp.json::<GithubCommitResponse>()?.sha)
+    }
+
+    async fn list_refs(&self, _cache: CachePolicy) -> Result<Vec<String>> {
+        let tags = self.list_ref_names("tags").await?;
+        let branches = self.list_ref_names("branches").await?;
+        Ok(order_refs(tags, branches))
+    }
+}
+
+fn api_request(http: &HttpClient, url: Url, media_type: &str) -> RequestBuilder {
+    http.get(url).header("Accept", media_type)
+}
+
+/// Checks whether the contents API refused a file because of its size
+fn is_too_large(error: &serde_json::Value) -> bool {
+    error["errors"]
+        .as_array()
+        .is_some_and(|errors| errors.iter().any(|err| err["code"] == "too_large"))
+}
+
+/// Creates the JWT a GitHub App authenticates as itself with
+async fn app_jwt(app_id: u64, private_key: &Path) -> Result<String> {
+    let pem = tokio::fs::read(private_key)
+        .await
+        .with_context(|| format!("Failed to read private key `{}`", private_key.display()))?;
+
+    let key = EncodingKey::from_rsa_pem(&pem)
+        .with_context(|| format!("Failed to parse private key `{}`", private_key.display()))?;
+
+    // Issued in the past to allow for clock drift, as GitHub recommends
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let claims = GithubAppClaims {
+        iat: now - 60,
+        exp: now + APP_JWT_LIFETIME,
+        iss: app_id.to_string(),
+    };
+
+    Ok(jsonwebtoken::encode(
+        &Header::new(Algorithm::RS256),
+        &claims,
+        &key,
+    )?)
+}
+
+/// Looks up the installation of a GitHub App on the given repo
+async fn find_installation(
+    http: &HttpClient,
+    api_url: &str,
+    project_id: &str,
+    app: &GithubApp,
+) -> Result<u64> {
+    let mut segments = vec!["repos"];
+    segments.extend(project_id.split('/'));
+    segments.push("installation");
+
+    let installation_url = build_url(api_url, &segments, &[])?;
+    let req = api_request(http, installation_url, JSON_MEDIA_TYPE)
+        .bearer_auth(app_jwt(app.app_id, &app.private_key).await?);
+
+    let resp = http.send(req).await?;
+    if !resp.status().is_success() {
+        bail!(
+            "GitHub App {} is not installed on `{}`: {}",
+            app.app_id,
+            project_id,
+            resp.text().await?
         );
+    }
+
+    Ok(resp.json::<GithubInstallationResponse>().await?.id)
+}
+
+impl GithubRepo {
+    fn api_url(&self) -> &str {
+        self.api_url.as_deref().unwrap_or(API_URL)
+    }
+
+    /// Builds the URL of an endpoint below `/repos/<owner>/<repo>`
+    fn repo_url(&self, segments: &[&str], query: &[(&str, &str)]) -> Result<Url> {
+        let mut path = vec!["repos"];
+        path.extend(self.project_id.split('/'));
+        path.extend(segments);
+        build_url(self.api_url(), &path, query)
+    }
 
-        let req = reqwest::Client::new()
-            .get(script_url)
-            .header("Accept", "application/vnd.github.v3+json")
-            .header("User-Agent", "rem-bash");
+    /// The contents API takes the path as it is, with its slashes
+    fn contents_url(&self, path: &str, repo_ref: &str) -> Result<Url> {
+        let mut segments = vec!["contents"];
+        segments.extend(path.split('/').filter(|segment| !segment.is_empty()));
+        self.repo_url(&segments, &[("ref", repo_ref)])
+    }
 
+    async fn authorize(&self, req: RequestBuilder) -> Result<RequestBuilder> {
         let auth = match &self.auth {
-            Some(auth) => {
-                let password = match &auth.password {
-                    GithubPassword::Saved(saved) => saved.to_string(),
-                    GithubPassword::FromEnv(var) => env::var(var)?,
-                };
+            Some(auth) => auth,
+            None => return Ok(req),
+        };
 
-                Some((auth.username.clone(), password))
+        match auth {
+            GithubAuth::Basic { username, password } => {
+                Ok(req.basic_auth(username.clone(), Some(password.get()?)))
             }
-            None => None,
-        };
+            GithubAuth::Token { token } => Ok(req.bearer_auth(token.get()?)),
+            GithubAuth::App {
+                app_id,
+                installation_id,
+                private_key,
+            } => {
+                let token = self
+                    .installation_token(*app_id, *installation_id, private_key)
+                    .await?;
 
-        let req = match auth {
-            Some((username, password)) => req.basic_auth(username, Some(password)),
-            _ => req,
-        };
+                Ok(req.bearer_auth(token))
+            }
+        }
+    }
 
-        let resp = req.send().await?;
+    /// Returns a token for the app installation, reusing the cached one
+    /// until it's about to expire
+    async fn installation_token(
+        &self,
+        app_id: u64,
+        installation_id: u64,
+        private_key: &Path,
+    ) -> Result<String> {
+        let mut cache_path = self.http.cache_dir(APP_TOKEN_CACHE_DIR).await?;
+        cache_path.push(format!("{}-{}.json", app_id, installation_id));
+
+        if let Ok(contents) = tokio::fs::read_to_string(&cache_path).await {
+            if let Ok(cached) = serde_json::from_str::<GithubInstallationToken>(&contents) {
+                let expires_at = humantime::parse_rfc3339(&cached.expires_at);
+                if expires_at.is_ok_and(|at| at > SystemTime::now() + APP_TOKEN_EXPIRY_MARGIN) {
+                    return Ok(cached.token);
+                }
+            }
+        }
+
+        let installation_id = installation_id.to_string();
+        let token_url = build_url(
+            self.api_url(),
+            &["app", "installations", &installation_id, "access_tokens"],
+            &[],
+        )?;
+
+        let req = self
+            .http
+            .post(token_url)
+            .header("Accept", JSON_MEDIA_TYPE)
+            .bearer_auth(app_jwt(app_id, private_key).await?);
+
+        let resp = self.http.send(req).await?;
         if !resp.status().is_success() {
-            bail!(
-                "Got error response from gitlab: {}",
-                resp.json::<serde_json::Value>().await?
-            );
+            return Err(response_error(PROVIDER, resp).await);
         }
 
-        let resp = resp.json::<GithubFileResponse>().await?;
-        let content = reqwest::Client::new()
-            .get(&resp.download_url)
-            .header("User-Agent", "rem-bash")
-            .send()
+        let token = resp.json::<GithubInstallationToken>().await?;
+
+        // The token grants access to the repo, so only we may read it
+        let contents = serde_json::to_string(&token)?;
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&cache_path)
+            .await
+            .context("Failed to write installation token cache")?;
+
+        file.write_all(contents.as_bytes()).await?;
+        file.flush().await?;
+
+        Ok(token.token)
+    }
+
+    async fn list_contents(&self, dir: &str, repo_ref: &str) -> Result<Vec<GithubContentsEntry>> {
+        let contents_url = self.contents_url(dir, repo_ref)?;
+        let req = self
+            .authorize(api_request(&self.http, contents_url, JSON_MEDIA_TYPE))
+            .await?;
+        let resp = self.http.send(req).await?;
+        if !resp.status().is_success() {
+            return Err(response_error(PROVIDER, resp).await);
+        }
+
+        // The contents API returns a single object instead of a list for files
+        let entries = match resp.json::<serde_json::Value>().await? {
+            serde_json::Value::Array(entries) => entries,
+            _ => bail!("`{}` is not a directory", dir),
+        };
+
+        Ok(entries
+            .into_iter()
+            .map(serde_json::from_value)
+            .collect::<Result<_, _>>()?)
+    }
+
+    /// Looks up the blob of a file through its parent directory, since the
+    /// contents API doesn't return anything for files that are too large
+    async fn find_blob(&self, path: &str, repo_ref: &str) -> Result<String> {
+        let dir = path.rsplit_once('/').map_or("", |(dir, _)| dir);
+        self.list_contents(dir, repo_ref)
             .await?
-            .text()
+            .into_iter()
+            .find(|entry| entry.path == path && entry.entry_type == "file")
+            .map(|entry| entry.sha)
+            .ok_or_else(|| anyhow!("`{}` was not found", path))
+    }
+
+    /// Lists the names of the first 100 tags or branches, which is plenty
+    /// for finding a recent one
+    async fn list_ref_names(&self, kind: &str) -> Result<Vec<String>> {
+        let refs_url = self.repo_url(&[kind], &[("per_page", "100")])?;
+        let req = self
+            .authorize(api_request(&self.http, refs_url, JSON_MEDIA_TYPE))
+            .await?;
+        let resp = self.http.send(req).await?;
+        if !resp.status().is_success() {
+            return Err(response_error(PROVIDER, resp).await);
+        }
+
+        let refs = resp.json::<Vec<GithubRef>>().await?;
+        Ok(refs.into_iter().map(|rref| rref.name).collect())
+    }
+
+    async fn fetch_blob(&self, sha: &str) -> Result<Vec<u8>> {
+        let blob_url = self.repo_url(&["git", "blobs", sha], &[])?;
+        let req = self
+            .authorize(api_request(&self.http, blob_url, RAW_MEDIA_TYPE))
             .await?;
+        let resp = self.http.send_cached(req).await?;
+        if !resp.status().is_success() {
+            return Err(resp.error(PROVIDER));
+        }
+
+        Ok(resp.into_bytes())
+    }
+
+    async fn list_tree(&self, dir: &str, repo_ref: &str) -> Result<Vec<RepoEntry>> {
+        let tree_url = self.repo_url(&["git", "trees", repo_ref], &[("recursive", "1")])?;
+        let req = self
+            .authorize(api_request(&self.http, tree_url, JSON_MEDIA_TYPE))
+            .await?;
+        let resp = self.http.send(req).await?;
+        if !resp.status().is_success() {
+            return Err(response_error(PROVIDER, resp).await);
+        }
+
+        let resp = resp.json::<GithubTreeResponse>().await?;
+        if resp.truncated {
+            bail!("Repository tree is too large to be listed through the github API");
+        }
 
-        Ok(content)
+        let prefix = match dir {
+            "" => String::new(),
+            dir => format!("{}/", dir),
+        };
+
+        let entries = resp
+            .tree
+            .into_iter()
+            .filter(|entry| entry.entry_type == "blob" && entry.path.starts_with(&prefix))
+            .map(|entry| RepoEntry {
+                path: entry.path,
+                is_dir: false,
+            })
+            .collect();
+
+        Ok(entries)
     }
 }
 
@@ -99,44 +543,457 @@ pub async fn fetch_project(
     uri: &Url,
     username: Option<String>,
     password: Password,
+    app: Option<GithubApp>,
+    http: &HttpClient,
 ) -> Result<Box<dyn Repo>> {
     let without_leading_slash = uri.path().trim_start_matches('/');
-    let repo_url = format!("https://api.github.com/repos/{}", without_leading_slash);
-    let req = reqwest::Client::new()
-        .get(repo_url)
-        .header("Accept", "application/vnd.github.v3+json")
-        .header("User-Agent", "rem-bash");
-
-    if username.is_some() && password == Password::None {
-        bail!("Github repo must have password if a username is used");
-    }
-
-    let (req, password_to_save) = match password {
-        Password::Saved(password) => (
-            req.basic_auth(username.clone().unwrap(), Some(password.clone())),
-            Some(GithubPassword::Saved(password)),
-        ),
-        Password::FromEnv(var, password) => (
-            req.basic_auth(username.clone().unwrap(), Some(password.clone())),
-            Some(GithubPassword::FromEnv(var)),
-        ),
-        _ => (req, None),
-    };
 
-    let resp = req.send().await?;
-    if !resp.status().is_success() {
-        bail!("Got error response from github: {}", resp.text().await?);
-    }
-
-    let auth = username.map(|username| GithubAuth {
-        username: username.to_string(),
-        password: password_to_save.unwrap(),
-    });
+    let auth = match (username, password, app) {
+        (_, _, Some(app)) => Some(GithubAuth::App {
+            app_id: app.app_id,
+            installation_id: find_installation(http, API_URL, without_leading_slash, &app).await?,
+            private_key: app.private_key,
+        }),
+        (Some(_), Password::None, None) => {
+            bail!("Github repo must have password if a username is used")
+        }
+        (Some(username), Password::Saved(password), None) => Some(GithubAuth::Basic {
+            username,
+            password: GithubPassword::Saved(password),
+        }),
+        (Some(username), Password::FromEnv(var, _), None) => Some(GithubAuth::Basic {
+            username,
+            password: GithubPassword::FromEnv(var),
+        }),
+        // Tokens without a username are sent as bearer tokens
+        (None, Password::Saved(token), None) => Some(GithubAuth::Token {
+            token: GithubPassword::Saved(token),
+        }),
+        (None, Password::FromEnv(var, _), None) => Some(GithubAuth::Token {
+            token: GithubPassword::FromEnv(var),
+        }),
+        (None, Password::None, None) => None,
+    };
 
     let result = GithubRepo {
         project_id: without_leading_slash.to_string(),
         auth,
+        options: RepoOptions::default(),
+        http: http.clone(),
+        api_url: None,
     };
 
+    let repo_url = result.repo_url(&[], &[])?;
+    let req = result
+        .authorize(api_request(http, repo_url, JSON_MEDIA_TYPE))
+        .await?;
+
+    let resp = http.send(req).await?;
+    if !resp.status().is_success() {
+        return Err(response_error(PROVIDER, resp).await);
+    }
+
     Ok(Box::new(result))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error::RemError, http::test_client};
+    use mockito::{Matcher, Server, ServerGuard};
+    use serde_json::json;
+
+    /// `user:token`, base64 encoded
+    const BASIC_AUTH: &str = "Basic dXNlcjp0b2tlbg==";
+
+    fn test_repo(server: &ServerGuard) -> GithubRepo {
+        GithubRepo {
+            project_id: "owner/scripts".to_owned(),
+            auth: Some(GithubAuth::Basic {
+                username: "user".to_owned(),
+                password: GithubPassword::Saved("token".to_owned()),
+            }),
+            options: RepoOptions::default(),
+            http: test_client(),
+            api_url: Some(server.url()),
+        }
+    }
+
+    async fn mock_contents(
+        server: &mut ServerGuard,
+        path: &str,
+        media_type: &str,
+        status: usize,
+        content_type: &str,
+        body: String,
+    ) -> mockito::Mock {
+        server
+            .mock(
+                "GET",
+                format!("/repos/owner/scripts/contents/{}", path).as_str(),
+            )
+            .match_query(Matcher::UrlEncoded("ref".to_owned(), "main".to_owned()))
+            .match_header("accept", media_type)
+            .match_header("authorization", BASIC_AUTH)
+            .with_status(status)
+            .with_header("content-type", content_type)
+            .with_body(body)
+            .create_async()
+            .await
+    }
+
+    async fn fetch(repo: &GithubRepo, path: &str) -> Result<String> {
+        repo.fetch_script(path, "main", CachePolicy::Fresh).await
+    }
+
+    #[tokio::test]
+    async fn fetches_file_in_single_authenticated_request() {
+        let mut server = Server::new_async().await;
+        let contents = mock_contents(
+            &mut server,
+            "deploy.sh",
+            RAW_MEDIA_TYPE,
+            200,
+            RAW_MEDIA_TYPE,
+            "echo deploy\n".to_owned(),
+        )
+        .await;
+
+        let repo = test_repo(&server);
+        assert_eq!(fetch(&repo, "deploy.sh").await.unwrap(), "echo deploy\n");
+        contents.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn fetches_large_file_through_blobs_api() {
+        let mut server = Server::new_async().await;
+        let too_large = json!({
+            "message": "This API returns blobs up to 1 MB in size.",
+            "errors": [{ "resource": "Blob", "field": "data", "code": "too_large" }],
+        });
+
+        let contents = mock_contents(
+            &mut server,
+            "bin/large.sh",
+            RAW_MEDIA_TYPE,
+            403,
+            "application/json; charset=utf-8",
+            too_large.to_string(),
+        )
+        .await;
+
+        let listing = json!([
+            { "path": "bin/other.sh", "type": "file", "sha": "other" },
+            { "path": "bin/large.sh", "type": "file", "sha": "abc123" },
+        ]);
+
+        let dir = mock_contents(
+            &mut server,
+            "bin",
+            JSON_MEDIA_TYPE,
+            200,
+            "application/json; charset=utf-8",
+            listing.to_string(),
+        )
+        .await;
+
+        let blob = server
+            .mock("GET", "/repos/owner/scripts/git/blobs/abc123")
+            .match_header("accept", RAW_MEDIA_TYPE)
+            .match_header("authorization", BASIC_AUTH)
+            .with_body("echo large\n")
+            .create_async()
+            .await;
+
+        let repo = test_repo(&server);
+        assert_eq!(fetch(&repo, "bin/large.sh").await.unwrap(), "echo large\n");
+        contents.assert_async().await;
+        dir.assert_async().await;
+        blob.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn rejects_symlink_outside_repo() {
+        let mut server = Server::new_async().await;
+        let symlink = json!({
+            "path": "link.sh",
+            "type": "symlink",
+            "sha": "abc123",
+            "target": "/etc/passwd",
+        });
+
+        mock_contents(
+            &mut server,
+            "link.sh",
+            RAW_MEDIA_TYPE,
+            200,
+            "application/json; charset=utf-8",
+            symlink.to_string(),
+        )
+        .await;
+
+        let repo = test_repo(&server);
+        let err = fetch(&repo, "link.sh").await.unwrap_err().to_string();
+        assert!(err.contains("is a symlink to `/etc/passwd`"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn rejects_submodule() {
+        let mut server = Server::new_async().await;
+        let submodule = json!({
+            "path": "vendor",
+            "type": "submodule",
+            "sha": "abc123",
+            "submodule_git_url": "git://github.com/owner/vendor.git",
+        });
+
+        mock_contents(
+            &mut server,
+            "vendor",
+            RAW_MEDIA_TYPE,
+            200,
+            "application/json; charset=utf-8",
+            submodule.to_string(),
+        )
+        .await;
+
+        let repo = test_repo(&server);
+        let err = fetch(&repo, "vendor").await.unwrap_err().to_string();
+        assert!(err.contains("is a submodule of"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn rejects_directory() {
+        let mut server = Server::new_async().await;
+        let listing = json!([{ "path": "bin/deploy.sh", "type": "file", "sha": "abc123" }]);
+
+        mock_contents(
+            &mut server,
+            "bin",
+            RAW_MEDIA_TYPE,
+            200,
+            "application/json; charset=utf-8",
+            listing.to_string(),
+        )
+        .await;
+
+        let repo = test_repo(&server);
+        let err = fetch(&repo, "bin").await.unwrap_err().to_string();
+        assert_eq!(err, "`bin` is a directory");
+    }
+
+    #[tokio::test]
+    async fn reports_error_responses() {
+        let mut server = Server::new_async().await;
+        mock_contents(
+            &mut server,
+            "missing.sh",
+            RAW_MEDIA_TYPE,
+            404,
+            "application/json; charset=utf-8",
+            json!({ "message": "Not Found" }).to_string(),
+        )
+        .await;
+
+        let repo = test_repo(&server);
+        let err = fetch(&repo, "missing.sh").await.unwrap_err();
+        assert!(
+            matches!(err.downcast_ref(), Some(RemError::NotFound(_))),
+            "{}",
+            err
+        );
+        assert!(err
+            .to_string()
+            .starts_with("Got error response from github"));
+    }
+
+    #[tokio::test]
+    async fn sends_token_without_username_as_bearer() {
+        let mut server = Server::new_async().await;
+        let contents = server
+            .mock("GET", "/repos/owner/scripts/contents/deploy.sh")
+            .match_query(Matcher::Any)
+            .match_header("authorization", "Bearer github_pat_123")
+            .with_header("content-type", RAW_MEDIA_TYPE)
+            .with_body("echo deploy\n")
+            .create_async()
+            .await;
+
+        let mut repo = test_repo(&server);
+        repo.auth = Some(GithubAuth::Token {
+            token: GithubPassword::Saved("github_pat_123".to_owned()),
+        });
+
+        assert_eq!(fetch(&repo, "deploy.sh").await.unwrap(), "echo deploy\n");
+        contents.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn reuses_app_installation_token_until_expiry() {
+        let mut server = Server::new_async().await;
+        let expires_at = SystemTime::now() + Duration::from_secs(60 * 60);
+        let token = json!({
+            "token": "ghs_installation",
+            "expires_at": humantime::format_rfc3339_seconds(expires_at).to_string(),
+        });
+
+        let access_tokens = server
+            .mock("POST", "/app/installations/42/access_tokens")
+            .match_header("authorization", Matcher::Regex("^Bearer ey".to_owned()))
+            .with_status(201)
+            .with_body(token.to_string())
+            .expect(1)
+            .create_async()
+            .await;
+
+        let contents = server
+            .mock("GET", "/repos/owner/scripts/contents/deploy.sh")
+            .match_query(Matcher::Any)
+            .match_header("authorization", "Bearer ghs_installation")
+            .with_header("content-type", RAW_MEDIA_TYPE)
+            .with_body("echo deploy\n")
+            .expect(2)
+            .create_async()
+            .await;
+
+        let mut repo = test_repo(&server);
+        repo.auth = Some(GithubAuth::App {
+            app_id: 7,
+            installation_id: 42,
+            private_key: PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/fixtures/github-app.pem"),
+        });
+
+        for _ in 0..2 {
+            assert_eq!(fetch(&repo, "deploy.sh").await.unwrap(), "echo deploy\n");
+        }
+
+        access_tokens.assert_async().await;
+        contents.assert_async().await;
+    }
+
+    #[test]
+    fn reads_saved_auth_variants() {
+        let basic: GithubAuth = toml::from_str(
+            r#"
+            username = "user"
+            password = { password_type = "FromEnv", password = "GITHUB_PASSWORD" }
+            "#,
+        )
+        .unwrap();
+        assert!(matches!(basic, GithubAuth::Basic { .. }));
+
+        let token: GithubAuth =
+            toml::from_str(r#"token = { password_type = "FromEnv", password = "GITHUB_TOKEN" }"#)
+                .unwrap();
+        assert!(matches!(token, GithubAuth::Token { .. }));
+
+        let app: GithubAuth = toml::from_str(
+            r#"
+            app_id = 7
+            installation_id = 42
+            private_key = "/etc/rem/app.pem"
+            "#,
+        )
+        .unwrap();
+        assert!(matches!(app, GithubAuth::App { .. }));
+    }
+
+    /// Paths that need encoding, with the path the API should receive
+    const AWKWARD_PATHS: &[(&str, &str)] = &[
+        ("deploy.sh", "deploy.sh"),
+        ("nested/dir/deploy.sh", "nested/dir/deploy.sh"),
+        ("with space.sh", "with%20space.sh"),
+        ("scripts/über.sh", "scripts/%C3%BCber.sh"),
+        ("hash#and?query.sh", "hash%23and%3Fquery.sh"),
+        ("100%.sh", "100%25.sh"),
+    ];
+
+    const AWKWARD_REFS: &[(&str, &str)] = &[
+        ("main", "main"),
+        ("feature/login", "feature%2Flogin"),
+        ("release#1", "release%231"),
+        ("v1.0+build", "v1.0+build"),
+    ];
+
+    #[tokio::test]
+    async fn encodes_awkward_paths_and_refs() {
+        let mut server = Server::new_async().await;
+        let mut mocks = Vec::new();
+        for (path, encoded_path) in AWKWARD_PATHS {
+            for (repo_ref, _) in AWKWARD_REFS {
+                let mock = server
+                    .mock(
+                        "GET",
+                        format!("/repos/owner/scripts/contents/{}", encoded_path).as_str(),
+                    )
+                    .match_query(Matcher::UrlEncoded("ref".to_owned(), repo_ref.to_string()))
+                    .with_header("content-type", RAW_MEDIA_TYPE)
+                    .with_body(format!("echo {}", path))
+                    .create_async()
+                    .await;
+
+                mocks.push((*path, *repo_ref, mock));
+            }
+        }
+
+        let repo = test_repo(&server);
+        for (path, repo_ref, mock) in mocks {
+            let content = repo
+                .fetch_script(path, repo_ref, CachePolicy::Fresh)
+                .await
+                .unwrap_or_else(|err| panic!("Fetching `{}@{}` failed: {}", path, repo_ref, err));
+
+            assert_eq!(content, format!("echo {}", path));
+            mock.assert_async().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn encodes_refs_in_commit_requests() {
+        let mut server = Server::new_async().await;
+        let repo = test_repo(&server);
+
+        for (repo_ref, encoded_ref) in AWKWARD_REFS {
+            let mock = server
+                .mock(
+                    "GET",
+                    format!("/repos/owner/scripts/commits/{}", encoded_ref).as_str(),
+                )
+                .with_body(json!({ "sha": encoded_ref }).to_string())
+                .create_async()
+                .await;
+
+            let commit = repo
+                .resolve_commit(repo_ref, CachePolicy::Fresh)
+                .await
+                .unwrap();
+
+            assert_eq!(&commit, encoded_ref);
+            mock.assert_async().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn fetches_non_utf8_files_unchanged() {
+        let contents =
+            b"#!/bin/bash\n# caf\xe9\nexit 0\n__ARCHIVE__\n\x1f\x8b\x08\x00\xff".to_vec();
+
+        let mut server = Server::new_async().await;
+        server
+            .mock("GET", "/repos/owner/scripts/contents/install.sh")
+            .match_query(Matcher::Any)
+            .with_header("content-type", RAW_MEDIA_TYPE)
+            .with_body(&contents)
+            .create_async()
+            .await;
+
+        let repo = test_repo(&server);
+        let fetched = repo
+            .fetch_bytes("install.sh", "main", CachePolicy::Fresh)
+            .await
+            .unwrap();
+
+        assert_eq!(fetched, contents);
+    }
+}
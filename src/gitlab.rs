@@ -1,18 +1,43 @@
 use anyhow::{bail, Result};
 use async_trait::async_trait;
+use reqwest::RequestBuilder;
 use serde::{Deserialize, Serialize};
-use std::env;
+use std::{env, path::PathBuf, str::FromStr};
 use url::Url;
 
-use crate::{repo::Repo, Password};
+use crate::{
+    http::{build_url, response_error, HttpClient},
+    repo::{order_refs, CachePolicy, Repo, RepoEntry, RepoLocation, RepoOptions},
+    Password,
+};
 
-pub const PROVIDER: &'static str = "gitlab";
+pub const PROVIDER: &str = "gitlab";
+
+const API_URL: &str = "https://gitlab.com/api/v4";
 
 #[derive(Debug, Deserialize)]
 struct GitlabFileResponse {
     content: String,
 }
 
+#[derive(Debug, Deserialize)]
+struct GitlabCommitResponse {
+    id: String,
+}
+
+/// A tag or branch, as listed by the API
+#[derive(Debug, Deserialize)]
+struct GitlabRef {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct GitlabTreeEntry {
+    path: String,
+    #[serde(rename = "type")]
+    entry_type: String,
+}
+
 #[derive(Debug, Deserialize)]
 struct GitlabRepoResponse {
     id: u32,
@@ -23,13 +48,103 @@ pub struct GitlabRepo {
     project_id: String,
     path: String,
     token: Option<GitlabToken>,
+
+    #[serde(flatten)]
+    options: RepoOptions,
+
+    #[serde(skip)]
+    http: HttpClient,
+
+    /// Only overridden to point to a mock server in tests
+    #[serde(skip)]
+    api_url: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
-#[serde(tag = "token_type", content = "token")]
+#[serde(tag = "token_type")]
 enum GitlabToken {
-    Saved(String),
-    FromEnv(String),
+    Saved {
+        token: String,
+        #[serde(default)]
+        token_kind: GitlabTokenKind,
+    },
+    FromEnv {
+        token: String,
+        #[serde(default)]
+        token_kind: GitlabTokenKind,
+    },
+}
+
+impl GitlabToken {
+    fn new(password: Password, token_kind: GitlabTokenKind) -> Option<Self> {
+        match password {
+            Password::Saved(token) => Some(GitlabToken::Saved { token, token_kind }),
+            Password::FromEnv(var, _) => Some(GitlabToken::FromEnv {
+                token: var,
+                token_kind,
+            }),
+            Password::None => None,
+        }
+    }
+
+    fn get(&self) -> Result<(String, GitlabTokenKind)> {
+        match self {
+            GitlabToken::Saved { token, token_kind } => Ok((token.clone(), *token_kind)),
+            GitlabToken::FromEnv { token, token_kind } => Ok((env::var(token)?, *token_kind)),
+        }
+    }
+}
+
+/// How a token is sent, which depends on where it came from
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum GitlabTokenKind {
+    /// Personal, project and group access tokens, sent as `PRIVATE-TOKEN`
+    #[default]
+    Private,
+    /// `CI_JOB_TOKEN` of a GitLab CI job, sent as `JOB-TOKEN`
+    Job,
+    /// OAuth and deploy tokens, sent as `Authorization: Bearer`
+    Bearer,
+}
+
+impl FromStr for GitlabTokenKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "private" => Ok(GitlabTokenKind::Private),
+            "job" => Ok(GitlabTokenKind::Job),
+            "bearer" => Ok(GitlabTokenKind::Bearer),
+            _ => bail!("Unknown token kind `{}`", s),
+        }
+    }
+}
+
+impl GitlabTokenKind {
+    fn apply(self, req: RequestBuilder, token: &str) -> RequestBuilder {
+        match self {
+            GitlabTokenKind::Private => req.header("PRIVATE-TOKEN", token),
+            GitlabTokenKind::Job => req.header("JOB-TOKEN", token),
+            GitlabTokenKind::Bearer => req.bearer_auth(token),
+        }
+    }
+}
+
+/// Returns the job token if we're running in a GitLab CI job on the given
+/// host. Jobs on other instances don't get it, so it isn't sent anywhere else.
+/// `var` looks up the job's environment variables.
+fn ci_job_token(host: &str, var: impl Fn(&str) -> Option<String>) -> Option<String> {
+    if var("GITLAB_CI")? != "true" {
+        return None;
+    }
+
+    let server_host = var("CI_SERVER_HOST")?;
+    if !server_host.eq_ignore_ascii_case(host) {
+        return None;
+    }
+
+    var("CI_JOB_TOKEN")
 }
 
 #[async_trait]
@@ -43,74 +158,446 @@ impl Repo for GitlabRepo {
         format!("gitlab.com/{}", &self.path)
     }
 
+    fn location(&self) -> RepoLocation {
+        RepoLocation {
+            host: "gitlab.com".to_owned(),
+            path: self.path.clone(),
+        }
+    }
+
+    fn options(&self) -> &RepoOptions {
+        &self.options
+    }
+
     fn box_clone(&self) -> Box<dyn Repo> {
         Box::new(self.clone())
     }
 
-    async fn fetch_script(&self, path: &str, repo_ref: &str, _fresh: bool) -> Result<String> {
-        let script_url = format!(
-            "https://gitlab.com/api/v4/projects/{}/repository/files/{}?ref={}",
-            self.project_id, path, repo_ref,
-        );
-
-        let req = reqwest::Client::new().get(script_url);
-        let token = match &self.token {
-            Some(GitlabToken::Saved(saved)) => Some(saved.clone()),
-            Some(GitlabToken::FromEnv(var)) => Some(env::var(var)?),
-            None => None,
-        };
+    fn set_http_client(&mut self, client: HttpClient) {
+        self.http = client;
+    }
 
-        let req = match token {
-            Some(token) => req.header("PRIVATE-TOKEN", token),
-            _ => req,
-        };
+    async fn fetch_bytes(
+        &self,
+        path: &str,
+        repo_ref: &str,
+        _cache: CachePolicy,
+    ) -> Result<Vec<u8>> {
+        // The file path has to be a single segment, with its slashes encoded
+        let script_url = self.project_url(&["repository", "files", path], &[("ref", repo_ref)])?;
 
-        let resp = req.send().await?;
+        let req = self.authorize(self.http.get(script_url))?;
+        let resp = self.http.send_cached(req).await?;
         if !resp.status().is_success() {
-            bail!(
-                "Got error response from gitlab: {}",
-                resp.json::<serde_json::Value>().await?
+            return Err(resp.error(PROVIDER));
+        }
+
+        let resp = resp.json::<GitlabFileResponse>()?;
+        Ok(base64::decode(resp.content)?)
+    }
+
+    async fn list_scripts(
+        &self,
+        dir: &str,
+        repo_ref: &str,
+        recursive: bool,
+        _cache: CachePolicy,
+    ) -> Result<Vec<RepoEntry>> {
+        let mut entries = Vec::new();
+        let mut page = "1".to_owned();
+
+        // The tree API is paginated, so we keep going until there's no next page
+        loop {
+            let tree_url = self.project_url(
+                &["repository", "tree"],
+                &[
+                    ("path", dir),
+                    ("ref", repo_ref),
+                    ("recursive", if recursive { "true" } else { "false" }),
+                    ("per_page", "100"),
+                    ("page", &page),
+                ],
+            )?;
+
+            let req = self.authorize(self.http.get(tree_url))?;
+            let resp = self.http.send(req).await?;
+            if !resp.status().is_success() {
+                return Err(response_error(PROVIDER, resp).await);
+            }
+
+            let next_page = resp
+                .headers()
+                .get("x-next-page")
+                .and_then(|next| next.to_str().ok())
+                .unwrap_or_default()
+                .to_owned();
+
+            let resp = resp.json::<Vec<GitlabTreeEntry>>().await?;
+            entries.extend(
+                resp.into_iter()
+                    .filter(|entry| match entry.entry_type.as_str() {
+                        "blob" => true,
+                        "tree" => !recursive,
+                        _ => false,
+                    })
+                    .map(|entry| RepoEntry {
+                        is_dir: entry.entry_type == "tree",
+                        path: entry.path,
+                    }),
             );
+
+            if next_page.is_empty() {
+                return Ok(entries);
+            }
+
+            page = next_page;
+        }
+    }
+
+    async fn checkout(&self, _repo_ref: &str, _cache: CachePolicy) -> Result<Option<PathBuf>> {
+        Ok(None)
+    }
+
+    async fn resolve_commit(&self, repo_ref: &str, _cache: CachePolicy) -> Result<String> {
+        let commit_url = self.project_url(&["repository", "commits", repo_ref], &[])?;
+
+        let req = self.authorize(self.http.get(commit_url))?;
+        let resp = self.http.send_cached(req).await?;
+        if !resp.status().is_success() {
+            return Err(resp.error(PROVIDER));
+        }
+
+        Ok(resp.json::<GitlabCommitResponse>()?.id)
+    }
+
+    async fn list_refs(&self, _cache: CachePolicy) -> Result<Vec<String>> {
+        let tags = self.list_ref_names("tags").await?;
+        let branches = self.list_ref_names("branches").await?;
+        Ok(order_refs(tags, branches))
+    }
+}
+
+impl GitlabRepo {
+    fn api_url(&self) -> &str {
+        self.api_url.as_deref().unwrap_or(API_URL)
+    }
+
+    /// Builds the URL of an endpoint below `/projects/<id>`
+    fn project_url(&self, segments: &[&str], query: &[(&str, &str)]) -> Result<Url> {
+        let mut path = vec!["projects", self.project_id.as_str()];
+        path.extend(segments);
+        build_url(self.api_url(), &path, query)
+    }
+
+    /// Lists the names of the first 100 tags or branches, which is plenty
+    /// for finding a recent one
+    async fn list_ref_names(&self, kind: &str) -> Result<Vec<String>> {
+        let refs_url = self.project_url(&["repository", kind], &[("per_page", "100")])?;
+
+        let req = self.authorize(self.http.get(refs_url))?;
+        let resp = self.http.send(req).await?;
+        if !resp.status().is_success() {
+            return Err(response_error(PROVIDER, resp).await);
         }
 
-        let resp = resp.json::<GitlabFileResponse>().await?;
-        let decoded_content = base64::decode(resp.content)?;
-        Ok(String::from_utf8(decoded_content)?)
+        let refs = resp.json::<Vec<GitlabRef>>().await?;
+        Ok(refs.into_iter().map(|rref| rref.name).collect())
+    }
+
+    /// Adds the saved token to the request. Repos without one use the job
+    /// token when running in GitLab CI.
+    fn authorize(&self, req: RequestBuilder) -> Result<RequestBuilder> {
+        let (token, token_kind) = match &self.token {
+            Some(token) => token.get()?,
+            None => match ci_job_token(&self.location().host, |name| env::var(name).ok()) {
+                Some(token) => (token, GitlabTokenKind::Job),
+                None => return Ok(req),
+            },
+        };
+
+        Ok(token_kind.apply(req, &token))
     }
 }
 
-pub async fn fetch_project(uri: &Url, token: Password) -> Result<Box<dyn Repo>> {
+pub async fn fetch_project(
+    uri: &Url,
+    token: Password,
+    token_kind: Option<GitlabTokenKind>,
+    http: &HttpClient,
+) -> Result<Box<dyn Repo>> {
     let without_leading_slash = uri.path().trim_start_matches('/');
-    let encoded_uri = urlencoding::encode(without_leading_slash);
-    let repo_url = format!("https://gitlab.com/api/v4/projects/{}", encoded_uri);
-    let req = reqwest::Client::new().get(repo_url);
-
-    let (req, token_to_save) = match token {
-        Password::Saved(token) => (
-            req.header("PRIVATE-TOKEN", token.clone()),
-            Some(GitlabToken::Saved(token)),
-        ),
-        Password::FromEnv(var, token) => (
-            req.header("PRIVATE-TOKEN", token),
-            Some(GitlabToken::FromEnv(var)),
-        ),
-        _ => (req, None),
+
+    // Job tokens are read from the job's environment unless given explicitly
+    let token = match (token, token_kind) {
+        (Password::None, Some(GitlabTokenKind::Job)) => {
+            Password::FromEnv("CI_JOB_TOKEN".to_owned(), String::new())
+        }
+        (Password::None, Some(_)) => bail!("A token kind was given without a token"),
+        (token, _) => token,
     };
 
-    let resp = req.send().await?;
+    let mut result = GitlabRepo {
+        project_id: String::new(),
+        token: GitlabToken::new(token, token_kind.unwrap_or_default()),
+        path: without_leading_slash.to_owned(),
+        options: RepoOptions::default(),
+        http: http.clone(),
+        api_url: None,
+    };
+
+    // Projects can be looked up by their full path instead of their ID
+    let repo_url = build_url(API_URL, &["projects", without_leading_slash], &[])?;
+    let req = result.authorize(http.get(repo_url))?;
+    let resp = http.send(req).await?;
     if !resp.status().is_success() {
-        bail!(
-            "Got error response from gitlab: {}",
-            resp.json::<serde_json::Value>().await?
-        );
+        return Err(response_error(PROVIDER, resp).await);
     }
 
     let resp = resp.json::<GitlabRepoResponse>().await?;
-    let result = GitlabRepo {
-        project_id: format!("{}", resp.id),
-        token: token_to_save,
-        path: without_leading_slash.to_owned(),
-    };
-
+    result.project_id = format!("{}", resp.id);
     Ok(Box::new(result))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error::RemError, http::test_client};
+    use mockito::{Matcher, Server, ServerGuard};
+    use serde_json::json;
+
+    fn test_repo(server: &ServerGuard, token: Option<GitlabToken>) -> GitlabRepo {
+        GitlabRepo {
+            project_id: "1234".to_owned(),
+            path: "owner/scripts".to_owned(),
+            token,
+            options: RepoOptions::default(),
+            http: test_client(),
+            api_url: Some(server.url()),
+        }
+    }
+
+    async fn mock_file(
+        server: &mut ServerGuard,
+        header: &str,
+        value: impl Into<Matcher>,
+    ) -> mockito::Mock {
+        let file = json!({ "content": base64::encode("echo deploy\n") });
+        server
+            .mock("GET", "/projects/1234/repository/files/deploy.sh")
+            .match_query(Matcher::Any)
+            .match_header(header, value)
+            .with_body(file.to_string())
+            .create_async()
+            .await
+    }
+
+    async fn fetch(repo: &GitlabRepo) -> Result<String> {
+        repo.fetch_script("deploy.sh", "main", CachePolicy::Fresh)
+            .await
+    }
+
+    #[tokio::test]
+    async fn sends_token_according_to_kind() {
+        let cases = [
+            (GitlabTokenKind::Private, "private-token", "secret"),
+            (GitlabTokenKind::Job, "job-token", "secret"),
+            (GitlabTokenKind::Bearer, "authorization", "Bearer secret"),
+        ];
+
+        for (token_kind, header, value) in cases {
+            let mut server = Server::new_async().await;
+            let file = mock_file(&mut server, header, value).await;
+
+            let token = GitlabToken::Saved {
+                token: "secret".to_owned(),
+                token_kind,
+            };
+
+            let repo = test_repo(&server, Some(token));
+            assert_eq!(fetch(&repo).await.unwrap(), "echo deploy\n");
+            file.assert_async().await;
+        }
+    }
+
+    #[test]
+    fn uses_job_token_in_gitlab_ci_on_same_host() {
+        let ci_env = |server_host: &'static str| {
+            move |name: &str| match name {
+                "GITLAB_CI" => Some("true".to_owned()),
+                "CI_SERVER_HOST" => Some(server_host.to_owned()),
+                "CI_JOB_TOKEN" => Some("job-secret".to_owned()),
+                _ => None,
+            }
+        };
+
+        let token = ci_job_token("gitlab.com", ci_env("GitLab.com"));
+        assert_eq!(token.as_deref(), Some("job-secret"));
+        assert_eq!(
+            ci_job_token("gitlab.com", ci_env("gitlab.example.com")),
+            None
+        );
+        assert_eq!(ci_job_token("gitlab.com", |_| None), None);
+    }
+
+    #[test]
+    fn reads_tokens_saved_without_kind() {
+        let token: GitlabToken = toml::from_str(
+            r#"
+            token_type = "FromEnv"
+            token = "GITLAB_TOKEN"
+            "#,
+        )
+        .unwrap();
+
+        match token {
+            GitlabToken::FromEnv { token, token_kind } => {
+                assert_eq!(token, "GITLAB_TOKEN");
+                assert_eq!(token_kind, GitlabTokenKind::Private);
+            }
+            _ => panic!("Token was not read from the environment"),
+        }
+    }
+
+    /// Paths that need encoding, with the path the API should receive. The
+    /// files API takes the whole path as a single segment.
+    const AWKWARD_PATHS: &[(&str, &str)] = &[
+        ("deploy.sh", "deploy.sh"),
+        ("nested/dir/deploy.sh", "nested%2Fdir%2Fdeploy.sh"),
+        ("with space.sh", "with%20space.sh"),
+        ("scripts/über.sh", "scripts%2F%C3%BCber.sh"),
+        ("hash#and?query.sh", "hash%23and%3Fquery.sh"),
+        ("100%.sh", "100%25.sh"),
+    ];
+
+    const AWKWARD_REFS: &[(&str, &str)] = &[
+        ("main", "main"),
+        ("feature/login", "feature%2Flogin"),
+        ("release#1", "release%231"),
+        ("v1.0+build", "v1.0+build"),
+    ];
+
+    #[tokio::test]
+    async fn encodes_awkward_paths_and_refs() {
+        let mut server = Server::new_async().await;
+        let mut mocks = Vec::new();
+        for (path, encoded_path) in AWKWARD_PATHS {
+            for (repo_ref, _) in AWKWARD_REFS {
+                let file = json!({ "content": base64::encode(format!("echo {}", path)) });
+                let mock = server
+                    .mock(
+                        "GET",
+                        format!("/projects/1234/repository/files/{}", encoded_path).as_str(),
+                    )
+                    .match_query(Matcher::UrlEncoded("ref".to_owned(), repo_ref.to_string()))
+                    .with_body(file.to_string())
+                    .create_async()
+                    .await;
+
+                mocks.push((*path, *repo_ref, mock));
+            }
+        }
+
+        let repo = test_repo(&server, None);
+        for (path, repo_ref, mock) in mocks {
+            let content = repo
+                .fetch_script(path, repo_ref, CachePolicy::Fresh)
+                .await
+                .unwrap_or_else(|err| panic!("Fetching `{}@{}` failed: {}", path, repo_ref, err));
+
+            assert_eq!(content, format!("echo {}", path));
+            mock.assert_async().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn encodes_refs_in_commit_and_tree_requests() {
+        let mut server = Server::new_async().await;
+        let repo = test_repo(&server, None);
+
+        for (repo_ref, encoded_ref) in AWKWARD_REFS {
+            let commit_mock = server
+                .mock(
+                    "GET",
+                    format!("/projects/1234/repository/commits/{}", encoded_ref).as_str(),
+                )
+                .with_body(json!({ "id": encoded_ref }).to_string())
+                .create_async()
+                .await;
+
+            let commit = repo
+                .resolve_commit(repo_ref, CachePolicy::Fresh)
+                .await
+                .unwrap();
+
+            assert_eq!(&commit, encoded_ref);
+            commit_mock.assert_async().await;
+
+            let tree_mock = server
+                .mock("GET", "/projects/1234/repository/tree")
+                .match_query(Matcher::AllOf(vec![
+                    Matcher::UrlEncoded("path".to_owned(), "with space/über".to_owned()),
+                    Matcher::UrlEncoded("ref".to_owned(), repo_ref.to_string()),
+                ]))
+                .with_body(json!([{ "path": "with space/über/a.sh", "type": "blob" }]).to_string())
+                .create_async()
+                .await;
+
+            let entries = repo
+                .list_scripts("with space/über", repo_ref, false, CachePolicy::Fresh)
+                .await
+                .unwrap();
+
+            assert_eq!(entries.len(), 1);
+            tree_mock.assert_async().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn fetches_non_utf8_files_unchanged() {
+        let contents =
+            b"#!/bin/bash\n# caf\xe9\nexit 0\n__ARCHIVE__\n\x1f\x8b\x08\x00\xff".to_vec();
+        let file = json!({ "content": base64::encode(&contents) });
+
+        let mut server = Server::new_async().await;
+        server
+            .mock("GET", "/projects/1234/repository/files/install.sh")
+            .match_query(Matcher::Any)
+            .with_body(file.to_string())
+            .create_async()
+            .await;
+
+        let repo = test_repo(&server, None);
+        let fetched = repo
+            .fetch_bytes("install.sh", "main", CachePolicy::Fresh)
+            .await
+            .unwrap();
+
+        assert_eq!(fetched, contents);
+    }
+
+    #[tokio::test]
+    async fn reports_invalid_token_as_unauthorized() {
+        let mut server = Server::new_async().await;
+        server
+            .mock("GET", "/projects/1234/repository/files/deploy.sh")
+            .match_query(Matcher::Any)
+            .with_status(401)
+            .with_body(r#"{"message":"401 Unauthorized"}"#)
+            .create_async()
+            .await;
+
+        let token = GitlabToken::Saved {
+            token: "expired".to_owned(),
+            token_kind: GitlabTokenKind::Private,
+        };
+
+        let repo = test_repo(&server, Some(token));
+        let err = fetch(&repo).await.unwrap_err();
+        assert!(
+            matches!(err.downcast_ref(), Some(RemError::Unauthorized(_))),
+            "{}",
+            err
+        );
+        assert_eq!(err.downcast_ref::<RemError>().unwrap().exit_code(), 67);
+    }
+}
@@ -0,0 +1,707 @@
+use crate::{config::get_cache_dir, error::RemError, report};
+use anyhow::{anyhow, Context, Result};
+use lazy_static::lazy_static;
+use reqwest::{
+    header::{CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+    tls::Certificate,
+    Client, IntoUrl, NoProxy, Proxy, RequestBuilder, Response, StatusCode,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{self, Permissions},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+use url::Url;
+
+const DEFAULT_CONNECT_TIMEOUT: u64 = 10;
+const DEFAULT_TIMEOUT: u64 = 60;
+const DEFAULT_RETRIES: u32 = 3;
+const DEFAULT_RATE_LIMIT_WAIT: u64 = 60;
+
+const RESPONSE_CACHE_DIR: &str = "http";
+
+/// Delay before the first retry, doubled for every following one
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+lazy_static! {
+    static ref DEFAULT_CLIENT: HttpClient =
+        HttpClient::new(&HttpConfig::default()).expect("Failed to create HTTP client");
+}
+
+/// Settings for requests to the provider APIs, from the `[http]` section
+/// of the config
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq)]
+#[serde(default)]
+pub struct HttpConfig {
+    /// Proxy used for all requests. If it isn't set, `HTTPS_PROXY`,
+    /// `HTTP_PROXY` and `NO_PROXY` are used.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    /// Comma separated hosts that don't use `proxy`, like `NO_PROXY`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub no_proxy: Option<String>,
+    /// PEM files with additional CA certificates to trust
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub ca_bundles: Vec<PathBuf>,
+    /// Seconds to wait for a connection to be established
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connect_timeout: Option<u64>,
+    /// Seconds a request may take, including reading the response
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    /// How often failed requests are retried, for connection errors and
+    /// server errors
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retries: Option<u32>,
+    /// Longest time in seconds to wait for an API rate limit to reset,
+    /// before failing instead
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rate_limit_wait: Option<u64>,
+}
+
+impl HttpConfig {
+    pub fn is_default(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+/// HTTP client shared by all providers, which retries failed requests
+/// with exponential backoff
+#[derive(Clone, Debug)]
+pub struct HttpClient {
+    client: Client,
+    retries: u32,
+    rate_limit_wait: Duration,
+    /// Where responses are cached, instead of rem's cache dir
+    cache_dir: Option<PathBuf>,
+}
+
+impl Default for HttpClient {
+    fn default() -> Self {
+        DEFAULT_CLIENT.clone()
+    }
+}
+
+impl HttpClient {
+    pub fn new(config: &HttpConfig) -> Result<Self> {
+        let connect_timeout = config.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT);
+        let timeout = config.timeout.unwrap_or(DEFAULT_TIMEOUT);
+
+        let mut builder = Client::builder()
+            .user_agent("rem-bash")
+            .connect_timeout(Duration::from_secs(connect_timeout))
+            .timeout(Duration::from_secs(timeout));
+
+        if let Some(proxy) = &config.proxy {
+            let no_proxy = match &config.no_proxy {
+                Some(no_proxy) => NoProxy::from_string(no_proxy),
+                None => NoProxy::from_env(),
+            };
+
+            let proxy = Proxy::all(proxy)
+                .with_context(|| format!("Invalid proxy `{}`", proxy))?
+                .no_proxy(no_proxy);
+
+            builder = builder.proxy(proxy);
+        }
+
+        for path in &config.ca_bundles {
+            let bundle = fs::read(path)
+                .with_context(|| format!("Failed to read CA bundle `{}`", path.display()))?;
+
+            let certs = Certificate::from_pem_bundle(&bundle)
+                .with_context(|| format!("Failed to parse CA bundle `{}`", path.display()))?;
+
+            for cert in certs {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+
+        Ok(Self {
+            client: builder.build().context("Failed to create HTTP client")?,
+            retries: config.retries.unwrap_or(DEFAULT_RETRIES),
+            rate_limit_wait: Duration::from_secs(
+                config.rate_limit_wait.unwrap_or(DEFAULT_RATE_LIMIT_WAIT),
+            ),
+            cache_dir: None,
+        })
+    }
+
+    /// Keeps cached responses and tokens in `dir` instead of rem's cache dir
+    pub fn with_cache_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.cache_dir = Some(dir.into());
+        self
+    }
+
+    /// Returns the directory to cache things for requests in, creating it
+    /// if it doesn't exist yet
+    pub async fn cache_dir(&self, name: &str) -> Result<PathBuf> {
+        let mut path = match &self.cache_dir {
+            Some(dir) => dir.clone(),
+            None => get_cache_dir().await?,
+        };
+
+        path.push(name);
+        tokio::fs::create_dir_all(&path).await?;
+        Ok(path)
+    }
+
+    pub fn get(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.client.get(url)
+    }
+
+    pub fn post(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.client.post(url)
+    }
+
+    /// Sends a request like `send`, but keeps successful responses with an
+    /// `ETag` or `Last-Modified` header in the cache dir. Later requests to
+    /// the same URL are made conditional, and a `304 Not Modified` is
+    /// answered from the cache. GitHub doesn't count those towards the
+    /// rate limit.
+    pub async fn send_cached(&self, req: RequestBuilder) -> Result<CachedResponse> {
+        let url = req
+            .try_clone()
+            .and_then(|req| req.build().ok())
+            .map(|req| req.url().to_string())
+            .unwrap_or_default();
+
+        let mut cache_path = self.cache_dir(RESPONSE_CACHE_DIR).await?;
+        cache_path.push(format!("{:x}.json", Sha256::digest(url.as_bytes())));
+        let cached = read_cache_entry(&cache_path).await;
+
+        let mut req = req;
+        if let Some(cached) = &cached {
+            if let Some(etag) = &cached.etag {
+                req = req.header(IF_NONE_MATCH, etag);
+            }
+
+            if let Some(last_modified) = &cached.last_modified {
+                req = req.header(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+
+        let resp = self.send(req).await?;
+        let status = resp.status();
+
+        if status == StatusCode::NOT_MODIFIED {
+            if let Some(cached) = cached {
+                let body = base64::decode(&cached.body).context("Corrupted response cache")?;
+                return Ok(CachedResponse {
+                    status: StatusCode::OK,
+                    content_type: cached.content_type,
+                    body,
+                });
+            }
+        }
+
+        let etag = header::<String>(&resp, ETAG.as_str());
+        let last_modified = header::<String>(&resp, LAST_MODIFIED.as_str());
+        let content_type = header::<String>(&resp, CONTENT_TYPE.as_str());
+        let body = resp.bytes().await?.to_vec();
+
+        if status.is_success() && (etag.is_some() || last_modified.is_some()) {
+            let entry = ResponseCacheEntry {
+                etag,
+                last_modified,
+                content_type: content_type.clone(),
+                body: base64::encode(&body),
+            };
+
+            // The cache only saves requests, so failing to write it is fine
+            let _ = write_cache_entry(&cache_path, &entry).await;
+        }
+
+        Ok(CachedResponse {
+            status,
+            content_type,
+            body,
+        })
+    }
+
+    /// Sends a request, retrying it on connection errors and server errors.
+    /// If the API's rate limit was hit, we wait for it to reset if that's
+    /// soon enough, and fail otherwise. Other responses are returned as is,
+    /// even if they're errors.
+    pub async fn send(&self, req: RequestBuilder) -> Result<Response> {
+        let mut backoff = INITIAL_BACKOFF;
+        let mut retries = 0;
+        let mut waited_for_limit = false;
+
+        loop {
+            // Requests with streaming bodies can't be retried
+            let attempt = match req.try_clone() {
+                Some(attempt) => attempt,
+                None => return Ok(req.send().await?),
+            };
+
+            let can_retry = retries < self.retries;
+            match attempt.send().await {
+                Ok(resp) => {
+                    if let Some(limit) = RateLimited::from_response(&resp) {
+                        let host = resp.url().host_str().unwrap_or_default();
+                        let wait = match limit.wait() {
+                            Some(wait) if !waited_for_limit && wait <= self.rate_limit_wait => wait,
+                            _ => return Err(limit.error(host)),
+                        };
+
+                        report::progress(format!(
+                            "Rate limit for `{}` exceeded, retrying in {}...",
+                            host,
+                            humantime::format_duration(wait)
+                        ));
+
+                        tokio::time::sleep(wait).await;
+                        waited_for_limit = true;
+                        continue;
+                    }
+
+                    if !resp.status().is_server_error() || !can_retry {
+                        return Ok(resp);
+                    }
+                }
+                Err(err) if (err.is_connect() || err.is_timeout()) && can_retry => {}
+                Err(err) => {
+                    let host = err.url().and_then(Url::host_str).unwrap_or_default();
+                    let message = format!("Request to `{}` failed", host);
+                    return Err(anyhow::Error::new(err).context(RemError::Network(message)));
+                }
+            }
+
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+            retries += 1;
+        }
+    }
+}
+
+/// Builds a URL from a base URL, path segments and query pairs. Every
+/// segment is percent-encoded on its own, so slashes in it are kept.
+pub fn build_url(base: &str, segments: &[&str], query: &[(&str, &str)]) -> Result<Url> {
+    let mut url = Url::parse(base).with_context(|| format!("Invalid API URL `{}`", base))?;
+    url.path_segments_mut()
+        .map_err(|_| anyhow!("Invalid API URL `{}`", base))?
+        .pop_if_empty()
+        .extend(segments);
+
+    if !query.is_empty() {
+        url.query_pairs_mut().extend_pairs(query);
+    }
+
+    Ok(url)
+}
+
+/// A response that was either received or served from the response cache
+pub struct CachedResponse {
+    status: StatusCode,
+    content_type: Option<String>,
+    body: Vec<u8>,
+}
+
+impl CachedResponse {
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    /// Checks whether the body is JSON, ignoring parameters like the charset
+    pub fn is_json(&self) -> bool {
+        self.content_type
+            .as_deref()
+            .and_then(|content_type| content_type.split(';').next())
+            .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("application/json"))
+    }
+
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T> {
+        Ok(serde_json::from_slice(&self.body)?)
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.body
+    }
+
+    pub fn error(&self, provider: &str) -> anyhow::Error {
+        status_error(provider, self.status, &self.body)
+    }
+}
+
+/// Turns an unsuccessful API response into an error
+pub async fn response_error(provider: &str, resp: Response) -> anyhow::Error {
+    let status = resp.status();
+    let body = resp.bytes().await.unwrap_or_default();
+    status_error(provider, status, &body)
+}
+
+/// The status decides the kind of error, and with that the exit code
+fn status_error(provider: &str, status: StatusCode, body: &[u8]) -> anyhow::Error {
+    let message = format!(
+        "Got error response from {} ({}): {}",
+        provider,
+        status,
+        String::from_utf8_lossy(body).trim()
+    );
+
+    match status {
+        StatusCode::NOT_FOUND => RemError::NotFound(message).into(),
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => RemError::Unauthorized(message).into(),
+        status if status.is_server_error() => RemError::Network(message).into(),
+        _ => anyhow!(message),
+    }
+}
+
+/// A cached response body, with the validators needed to check whether
+/// it's still up to date
+#[derive(Serialize, Deserialize)]
+struct ResponseCacheEntry {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    #[serde(default)]
+    content_type: Option<String>,
+    /// Base64 encoded, so binary bodies can be stored as well
+    body: String,
+}
+
+async fn read_cache_entry(path: &Path) -> Option<ResponseCacheEntry> {
+    let contents = tokio::fs::read_to_string(path).await.ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+/// Writes a cache entry that only we may read, since responses of private
+/// repos are cached as well
+async fn write_cache_entry(path: &Path, entry: &ResponseCacheEntry) -> Result<()> {
+    let contents = serde_json::to_string(entry)?;
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .await?;
+
+    // Entries written before may still be readable by others
+    file.set_permissions(Permissions::from_mode(0o600)).await?;
+    file.write_all(contents.as_bytes()).await?;
+    file.flush().await?;
+    Ok(())
+}
+
+fn header<T: FromStr>(resp: &Response, name: &str) -> Option<T> {
+    resp.headers().get(name)?.to_str().ok()?.parse().ok()
+}
+
+/// A response that was rejected by the API's rate limit
+struct RateLimited {
+    /// When the limit resets, if the API told us
+    reset_at: Option<SystemTime>,
+}
+
+impl RateLimited {
+    /// GitHub uses `X-RateLimit-*` headers and GitLab `RateLimit-*`, and
+    /// both may send `Retry-After` instead
+    fn from_response(resp: &Response) -> Option<Self> {
+        let status = resp.status();
+        if status != StatusCode::FORBIDDEN && status != StatusCode::TOO_MANY_REQUESTS {
+            return None;
+        }
+
+        let retry_after = header::<u64>(resp, "retry-after");
+        let remaining = header::<u64>(resp, "x-ratelimit-remaining")
+            .or_else(|| header(resp, "ratelimit-remaining"));
+
+        let reset =
+            header::<u64>(resp, "x-ratelimit-reset").or_else(|| header(resp, "ratelimit-reset"));
+
+        // A 403 is only caused by a rate limit if the API says so
+        let limited = status == StatusCode::TOO_MANY_REQUESTS
+            || retry_after.is_some()
+            || remaining == Some(0);
+
+        if !limited {
+            return None;
+        }
+
+        let reset_at = match (retry_after, reset) {
+            (Some(secs), _) => Some(SystemTime::now() + Duration::from_secs(secs)),
+            (None, Some(epoch)) => Some(UNIX_EPOCH + Duration::from_secs(epoch)),
+            (None, None) => None,
+        };
+
+        Some(Self { reset_at })
+    }
+
+    /// Time until the limit resets, rounded up to whole seconds, since
+    /// that's what the APIs give us
+    fn wait(&self) -> Option<Duration> {
+        let wait = self
+            .reset_at?
+            .duration_since(SystemTime::now())
+            .unwrap_or_default();
+
+        Some(Duration::from_secs(wait.as_secs() + 1))
+    }
+
+    fn error(&self, host: &str) -> anyhow::Error {
+        let message = match (self.reset_at, self.wait()) {
+            (Some(reset_at), Some(wait)) => format!(
+                "Rate limit for `{}` exceeded, it resets at {} (in {})",
+                host,
+                humantime::format_rfc3339_seconds(reset_at),
+                humantime::format_duration(wait)
+            ),
+            _ => format!("Rate limit for `{}` exceeded", host),
+        };
+
+        RemError::RateLimited(message).into()
+    }
+}
+
+/// A client for tests, which caches in its own temporary dir instead of
+/// the user's cache dir
+#[cfg(test)]
+pub fn test_client() -> HttpClient {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let dir = std::env::temp_dir().join(format!("rem-http-test-{}-{}", std::process::id(), id));
+    HttpClient::default().with_cache_dir(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::{Matcher, Server, ServerGuard};
+
+    const ETAG_VALUE: &str = "\"abc\"";
+    const LAST_MODIFIED_VALUE: &str = "Wed, 21 Oct 2015 07:28:00 GMT";
+
+    fn client(rate_limit_wait: u64) -> HttpClient {
+        let config = HttpConfig {
+            retries: Some(0),
+            rate_limit_wait: Some(rate_limit_wait),
+            ..HttpConfig::default()
+        };
+
+        HttpClient::new(&config).unwrap()
+    }
+
+    async fn send(client: &HttpClient, server: &ServerGuard) -> Result<Response> {
+        client
+            .send(client.get(format!("{}/limited", server.url())))
+            .await
+    }
+
+    /// Seconds since the epoch, an hour from now
+    fn in_an_hour() -> u64 {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        now.as_secs() + 3600
+    }
+
+    fn rate_limit_message(err: &anyhow::Error) -> String {
+        match err.downcast_ref() {
+            Some(RemError::RateLimited(message)) => message.clone(),
+            _ => panic!("Expected a rate limit error, got {:#}", err),
+        }
+    }
+
+    #[tokio::test]
+    async fn waits_for_retry_after_and_retries() {
+        let mut server = Server::new_async().await;
+        let limited = server
+            .mock("GET", "/limited")
+            .with_status(429)
+            .with_header("retry-after", "0")
+            .expect(1)
+            .create_async()
+            .await;
+        let ok = server
+            .mock("GET", "/limited")
+            .with_body("ok")
+            .create_async()
+            .await;
+
+        let resp = send(&client(5), &server).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        limited.assert_async().await;
+        ok.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn waits_for_a_rate_limit_only_once() {
+        let mut server = Server::new_async().await;
+        let limited = server
+            .mock("GET", "/limited")
+            .with_status(429)
+            .with_header("retry-after", "0")
+            .expect(2)
+            .create_async()
+            .await;
+
+        let err = send(&client(5), &server).await.unwrap_err();
+        assert!(rate_limit_message(&err).contains("resets at"));
+        limited.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn fails_when_github_limit_resets_too_late() {
+        let mut server = Server::new_async().await;
+        let reset = in_an_hour();
+        let limited = server
+            .mock("GET", "/limited")
+            .with_status(403)
+            .with_header("x-ratelimit-remaining", "0")
+            .with_header("x-ratelimit-reset", &reset.to_string())
+            .expect(1)
+            .create_async()
+            .await;
+
+        let err = send(&client(60), &server).await.unwrap_err();
+        let reset_at = humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(reset));
+        let message = rate_limit_message(&err);
+        assert!(message.starts_with("Rate limit for `127.0.0.1` exceeded, it resets at "));
+        assert!(message.contains(&reset_at.to_string()));
+        limited.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn reads_gitlab_rate_limit_reset() {
+        let mut server = Server::new_async().await;
+        let reset = in_an_hour();
+        server
+            .mock("GET", "/limited")
+            .with_status(429)
+            .with_header("ratelimit-remaining", "0")
+            .with_header("ratelimit-reset", &reset.to_string())
+            .create_async()
+            .await;
+
+        let err = send(&client(60), &server).await.unwrap_err();
+        let reset_at = humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(reset));
+        assert!(rate_limit_message(&err).contains(&reset_at.to_string()));
+    }
+
+    #[tokio::test]
+    async fn retry_after_takes_precedence_over_reset() {
+        let mut server = Server::new_async().await;
+        server
+            .mock("GET", "/limited")
+            .with_status(403)
+            .with_header("retry-after", "120")
+            .with_header("x-ratelimit-reset", &in_an_hour().to_string())
+            .create_async()
+            .await;
+
+        let err = send(&client(60), &server).await.unwrap_err();
+        assert!(rate_limit_message(&err).ends_with("(in 2m)"));
+    }
+
+    #[tokio::test]
+    async fn fails_without_reset_time() {
+        let mut server = Server::new_async().await;
+        server
+            .mock("GET", "/limited")
+            .with_status(429)
+            .create_async()
+            .await;
+
+        let err = send(&client(60), &server).await.unwrap_err();
+        assert_eq!(
+            rate_limit_message(&err),
+            "Rate limit for `127.0.0.1` exceeded"
+        );
+    }
+
+    #[tokio::test]
+    async fn returns_forbidden_responses_without_rate_limit() {
+        let mut server = Server::new_async().await;
+        server
+            .mock("GET", "/limited")
+            .with_status(403)
+            .with_header("x-ratelimit-remaining", "42")
+            .create_async()
+            .await;
+
+        let resp = send(&client(60), &server).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    async fn send_cached(client: &HttpClient, server: &ServerGuard) -> CachedResponse {
+        let req = client.get(format!("{}/file", server.url()));
+        client.send_cached(req).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn serves_not_modified_responses_from_cache() {
+        let mut server = Server::new_async().await;
+        let fresh = server
+            .mock("GET", "/file")
+            .with_header("etag", ETAG_VALUE)
+            .with_header("last-modified", LAST_MODIFIED_VALUE)
+            .with_header("content-type", "application/json; charset=utf-8")
+            .with_body("{\"id\":1}")
+            .expect(1)
+            .create_async()
+            .await;
+        let not_modified = server
+            .mock("GET", "/file")
+            .match_header("if-none-match", ETAG_VALUE)
+            .match_header("if-modified-since", LAST_MODIFIED_VALUE)
+            .with_status(304)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let client = test_client();
+        let first = send_cached(&client, &server).await;
+        let second = send_cached(&client, &server).await;
+        fresh.assert_async().await;
+        not_modified.assert_async().await;
+
+        assert_eq!(second.status(), StatusCode::OK);
+        assert!(second.is_json());
+        assert_eq!(second.into_bytes(), first.into_bytes());
+    }
+
+    #[tokio::test]
+    async fn caches_only_responses_with_validators() {
+        let mut server = Server::new_async().await;
+        let uncached = server
+            .mock("GET", "/file")
+            .match_header("if-none-match", Matcher::Missing)
+            .match_header("if-modified-since", Matcher::Missing)
+            .with_body("echo hi")
+            .expect(2)
+            .create_async()
+            .await;
+
+        let client = test_client();
+        send_cached(&client, &server).await;
+        send_cached(&client, &server).await;
+        uncached.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn cached_responses_are_only_readable_by_the_user() {
+        let mut server = Server::new_async().await;
+        server
+            .mock("GET", "/file")
+            .with_header("etag", ETAG_VALUE)
+            .with_body("echo secret")
+            .create_async()
+            .await;
+
+        let client = test_client();
+        send_cached(&client, &server).await;
+
+        let cache_dir = client.cache_dir(RESPONSE_CACHE_DIR).await.unwrap();
+        let entries = fs::read_dir(cache_dir).unwrap().collect::<Vec<_>>();
+        assert_eq!(entries.len(), 1);
+
+        let metadata = entries[0].as_ref().unwrap().metadata().unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+    }
+}
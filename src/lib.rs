@@ -0,0 +1,699 @@
+//! Resolves, fetches and runs scripts from saved repositories and raw git
+//! repositories. The `rem` binary is a thin CLI on top of this crate.
+
+use crate::{
+    audit::{AuditAction, AuditRecord},
+    config::Config,
+    error::RemError,
+    github::GithubApp,
+    gitlab::GitlabTokenKind,
+    http::HttpClient,
+    meta::ScriptMeta,
+    repo::{Repo, RepoEntry, RepoFile, RunOptions, ScriptInput},
+    trust::ReviewState,
+};
+use anyhow::{bail, Result};
+use async_process::ExitStatus;
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::{
+    os::unix::process::ExitStatusExt,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+use url::Url;
+
+pub mod audit;
+pub mod config;
+pub mod error;
+pub mod git;
+pub mod github;
+pub mod gitlab;
+pub mod http;
+pub mod meta;
+pub mod plugin;
+pub mod policy;
+mod process;
+pub mod repo;
+pub mod report;
+pub mod sandbox;
+pub mod search;
+pub mod trust;
+
+/// A git URL with an optional ref, refs can contain anything but colons
+const GIT_REPO_PATTERN: &str = r"(?P<repo>((git|ssh|http(s)?)|(git@[\w\.]+))(:(//)?)([\w\./\-~]+)(\.git)?(/)?)(@(?P<ref>[^:]+))?";
+
+lazy_static! {
+    static ref API_SOURCE_REGEX: Regex =
+        Regex::new(r"(?P<alias>^\w+)(@(?P<ref>[^:]+))?:(?P<script>.*)$").unwrap();
+    static ref GIT_SOURCE_REGEX: Regex =
+        Regex::new(&format!(r"^{}:(?P<script>.*)$", GIT_REPO_PATTERN)).unwrap();
+    static ref GIT_REPO_REGEX: Regex = Regex::new(&format!("^{}$", GIT_REPO_PATTERN)).unwrap();
+}
+
+/// Credentials given when adding a repo. Passwords from the environment
+/// keep the variable's name, so only the name is saved.
+#[derive(PartialEq)]
+pub enum Password {
+    Saved(String),
+    FromEnv(String, String),
+    None,
+}
+
+/// What a script is resolved for, which decides the extension it needs
+#[derive(Clone, Copy)]
+pub enum ScriptAction {
+    Run,
+    Import,
+    Info,
+    List,
+}
+
+/// A script in a saved repo (`<repo>[@ref]:<path>`) or a raw git repo
+/// (`git@<url>[@ref]:<path>`)
+pub struct ScriptSource {
+    /// The saved repo's alias, or the git URL
+    pub repo: String,
+    pub source_type: SourceType,
+    pub script_name: String,
+    pub rref: Option<String>,
+    pub action: ScriptAction,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SourceType {
+    Git,
+    Saved,
+}
+
+impl ScriptSource {
+    pub fn parse(script: &str, action: ScriptAction) -> Result<ScriptSource> {
+        // Git sources go first, as refs may contain dots and `git@host` would
+        // otherwise be read as the alias `git` at the ref `host`
+        if let Some(matches) = GIT_SOURCE_REGEX.captures(script) {
+            let repo = matches
+                .name("repo")
+                .expect("No repo matched")
+                .as_str()
+                .to_owned();
+
+            let script_name = matches
+                .name("script")
+                .expect("No script name matched")
+                .as_str()
+                .to_owned();
+
+            let rref = matches.name("ref").map(|rref| rref.as_str().to_owned());
+            if let Some(rref) = &rref {
+                validate_ref(rref)?;
+            }
+
+            return Ok(Self {
+                source_type: SourceType::Git,
+                repo,
+                script_name,
+                rref,
+                action,
+            });
+        }
+
+        if let Some(matches) = API_SOURCE_REGEX.captures(script) {
+            let repo = matches
+                .name("alias")
+                .expect("No alias matched")
+                .as_str()
+                .to_owned();
+
+            let script_name = matches
+                .name("script")
+                .expect("No script name matched")
+                .as_str()
+                .to_owned();
+
+            let rref = matches.name("ref").map(|rref| rref.as_str().to_owned());
+            if let Some(rref) = &rref {
+                validate_ref(rref)?;
+            }
+
+            return Ok(Self {
+                source_type: SourceType::Saved,
+                repo,
+                script_name,
+                rref,
+                action,
+            });
+        }
+
+        Err(RemError::ParseError(format!("Script source `{}` could not be parsed", script)).into())
+    }
+
+    /// Parses a source for listing, where the directory may be left out
+    pub fn parse_dir(source: &str, action: ScriptAction) -> Result<ScriptSource> {
+        // A bare git repo would otherwise be read as an alias at a ref
+        if GIT_REPO_REGEX.is_match(source) {
+            return Self::parse(&format!("{}:", source), action);
+        }
+
+        Self::parse(source, action).or_else(|_| Self::parse(&format!("{}:", source), action))
+    }
+
+    pub fn validate_script_name(&self, config: &Config) -> Result<()> {
+        if self.script_name.is_empty() {
+            return Err(RemError::ParseError("No script path was given".to_owned()).into());
+        }
+
+        if config.require_bash_extension.is_none() && config.require_lib_extension.is_none() {
+            return Ok(());
+        }
+
+        let expected = match (
+            &config.require_bash_extension,
+            &config.require_lib_extension,
+            &self.action,
+        ) {
+            (Some(ref ext), _, &ScriptAction::Run) => ext,
+            (_, Some(ext), &ScriptAction::Import) => ext,
+            _ => return Ok(()),
+        };
+
+        if !self.script_name.ends_with(expected) {
+            let message = format!("Expected script name to end with `{}`", expected);
+            return Err(RemError::ParseError(message).into());
+        }
+
+        Ok(())
+    }
+
+    pub fn get_repo(&self, config: &Config) -> Result<Box<dyn Repo>> {
+        Ok(match self.source_type {
+            SourceType::Saved => config
+                .repo
+                .get(&self.repo)
+                .ok_or_else(|| RemError::NotFound(format!("Repo `{}` was not found", &self.repo)))?
+                .box_clone(),
+            SourceType::Git => git::GitRepo::from_src(self),
+        })
+    }
+
+    /// The repo's alias and where it points to, or only the latter for raw
+    /// git sources
+    pub fn repo_name(&self, repo: &dyn Repo) -> String {
+        match self.source_type {
+            SourceType::Saved => {
+                format!("{} ({} | {})", self.repo, repo.provider(), repo.readable())
+            }
+            SourceType::Git => format!("{} ({})", repo.readable(), repo.provider()),
+        }
+    }
+
+    pub fn get_ref(&self) -> String {
+        self.rref.clone().unwrap_or("HEAD".to_owned())
+    }
+
+    /// Checks the policy, then fetches the script together with the commit
+    /// it was fetched at
+    pub async fn fetch(&self, config: &Config, fresh: bool) -> Result<FetchedScript> {
+        let repo = self.get_repo(config)?;
+        self.check_policy(config, repo.as_ref())?;
+
+        let cache = config.cache_policy(repo.as_ref(), fresh);
+        let (contents, commit) = repo
+            .fetch_at_commit(&self.script_name, &self.get_ref(), cache)
+            .await?;
+
+        Ok(FetchedScript { contents, commit })
+    }
+
+    pub fn check_policy(&self, config: &Config, repo: &dyn Repo) -> Result<()> {
+        let alias = match self.source_type {
+            SourceType::Saved => Some(self.repo.as_str()),
+            SourceType::Git => None,
+        };
+
+        config.check_policy(alias, &repo.location(), &self.get_ref())
+    }
+
+    /// Fetches the files next to the script, at the commit it was fetched
+    /// at, so they can be reviewed and materialized with it
+    pub async fn fetch_siblings(
+        &self,
+        config: &Config,
+        script: &FetchedScript,
+    ) -> Result<Vec<RepoFile>> {
+        let repo = self.get_repo(config)?;
+        // Reuses the checkout the script was fetched from, if there is one
+        let cache = config.cache_policy(repo.as_ref(), false);
+        let path = &self.script_name;
+        repo::fetch_siblings(repo.as_ref(), path, script, &self.get_ref(), cache).await
+    }
+
+    /// Variables that let a script know where it was loaded from
+    pub fn provenance_env(&self, commit: &str) -> Vec<(String, String)> {
+        vec![
+            ("REM_SCRIPT".to_owned(), self.script_name.clone()),
+            ("REM_REPO".to_owned(), self.repo.clone()),
+            ("REM_REF".to_owned(), self.get_ref()),
+            ("REM_COMMIT".to_owned(), commit.to_owned()),
+        ]
+    }
+
+    pub async fn list_scripts(
+        &self,
+        config: &Config,
+        fresh: bool,
+        recursive: bool,
+    ) -> Result<Vec<RepoEntry>> {
+        let repo = self.get_repo(config)?;
+        let cache = config.cache_policy(repo.as_ref(), fresh);
+        let dir = self.script_name.trim_matches('/');
+        repo.list_scripts(dir, &self.get_ref(), recursive, cache)
+            .await
+    }
+
+    /// Runs a fetched script and records the run in the audit log, once
+    /// when it starts and again when it ends. With `siblings`, the script
+    /// is written to a temporary directory together with them and run from
+    /// there.
+    pub async fn run(
+        &self,
+        config: &Config,
+        script: &FetchedScript,
+        args: &[String],
+        opts: &RunOptions,
+        siblings: Option<&[RepoFile]>,
+    ) -> Result<ExitStatus> {
+        let materialized = match siblings {
+            Some(siblings) => {
+                Some(repo::materialize_script(&self.script_name, &script.contents, siblings).await?)
+            }
+            None => None,
+        };
+
+        let mut record = AuditRecord::new(AuditAction::Run, self, script, args);
+
+        // Runs are recorded before they start, so they're logged even if
+        // rem is killed while the script is running
+        if let Err(err) = audit::append(config.audit_log.as_ref(), &record).await {
+            if let Some(materialized) = materialized {
+                let _ = materialized.cleanup().await;
+            }
+
+            return Err(err);
+        }
+
+        let input = match &materialized {
+            Some(materialized) => ScriptInput::File(&materialized.script_path),
+            None => ScriptInput::Inline(&script.contents),
+        };
+
+        let started = Instant::now();
+        let script_args = args.iter().map(|s| &**s).collect();
+        let exit = repo::run_script(input, script_args, opts).await;
+
+        // The script already ran, so failing to clean up or to record its
+        // end must not change the exit status
+        if let Some(materialized) = materialized {
+            if let Err(err) = materialized.cleanup().await {
+                report::warn(format!("Failed to remove materialized files: {:#}", err));
+            }
+        }
+
+        record.finish(started.elapsed(), exit.as_ref().ok().and_then(|s| s.code()));
+        if let Err(err) = audit::append(config.audit_log.as_ref(), &record).await {
+            report::warn(format!("Failed to record the end of the run: {:#}", err));
+        }
+
+        exit
+    }
+
+    /// Prints a fetched script to stdout and records the import in the
+    /// audit log
+    pub async fn import(&self, config: &Config, script: &FetchedScript) -> Result<()> {
+        let mut record = AuditRecord::new(AuditAction::Import, self, script, &[]);
+        let started = Instant::now();
+        repo::import_script(&script.contents).await?;
+
+        record.finish(started.elapsed(), None);
+        audit::append(config.audit_log.as_ref(), &record).await
+    }
+}
+
+/// The script followed by its siblings, as paths and contents
+fn reviewed_files<'a>(
+    src: &'a ScriptSource,
+    script: &'a FetchedScript,
+    siblings: Option<&'a [RepoFile]>,
+) -> Vec<(&'a str, &'a [u8])> {
+    let siblings = siblings.unwrap_or_default().iter();
+    std::iter::once((src.script_name.as_str(), script.contents.as_slice()))
+        .chain(siblings.map(|file| (file.path.as_str(), file.contents.as_slice())))
+        .collect()
+}
+
+/// Rejects refs that `git check-ref-format --allow-onelevel` rejects, like
+/// ones containing `..` or starting with `/`
+fn validate_ref(rref: &str) -> Result<()> {
+    let invalid_char = |c: char| c.is_ascii_control() || " ~^:?*[\\".contains(c);
+    let invalid_component = |component: &str| {
+        component.is_empty() || component.starts_with('.') || component.ends_with(".lock")
+    };
+
+    let valid = rref != "@"
+        && !rref.contains("..")
+        && !rref.contains("@{")
+        && !rref.ends_with('.')
+        && !rref.chars().any(invalid_char)
+        && !rref.split('/').any(invalid_component);
+
+    if !valid {
+        let message = format!("`{}` is not a valid ref", rref);
+        return Err(RemError::ParseError(message).into());
+    }
+
+    Ok(())
+}
+
+/// A script's contents and the commit they were fetched at
+pub struct FetchedScript {
+    pub contents: Vec<u8>,
+    pub commit: String,
+}
+
+/// How to run a script, e.g. as given by `rem run`'s flags. Unset values
+/// fall back to the repo's defaults.
+pub struct RunRequest {
+    pub args: Vec<String>,
+    /// Fetch the script again instead of using the cache
+    pub fresh: bool,
+    /// Variables to set, these take precedence over `env_file`, which takes
+    /// precedence over the repo's defaults
+    pub env: Vec<(String, String)>,
+    pub env_file: Option<PathBuf>,
+    pub clear_env: bool,
+    pub keep_env: Vec<String>,
+    pub cwd: Option<PathBuf>,
+    /// Run the script from a directory with the files next to it
+    pub materialize: bool,
+    pub timeout: Option<Duration>,
+    pub grace_period: Duration,
+    /// Use a sandbox, with the default profile unless another one is given
+    pub sandbox: bool,
+    pub sandbox_profile: Option<String>,
+    pub no_network: bool,
+    pub bind: Vec<PathBuf>,
+    /// Don't use a sandbox, not even the repo's default one
+    pub no_sandbox: bool,
+    /// Accept the script without asking if it wasn't reviewed yet
+    pub yes: bool,
+}
+
+impl Default for RunRequest {
+    fn default() -> Self {
+        Self {
+            args: Vec::new(),
+            fresh: false,
+            env: Vec::new(),
+            env_file: None,
+            clear_env: false,
+            keep_env: Vec::new(),
+            cwd: None,
+            materialize: false,
+            timeout: None,
+            grace_period: process::DEFAULT_GRACE_PERIOD,
+            sandbox: false,
+            sandbox_profile: None,
+            no_network: false,
+            bind: Vec::new(),
+            no_sandbox: false,
+            yes: false,
+        }
+    }
+}
+
+/// A fetched script and everything needed to run it. Dry runs print the
+/// plan instead of running it.
+pub struct RunPlan<'a> {
+    pub src: &'a ScriptSource,
+    pub script: FetchedScript,
+    pub args: Vec<String>,
+    pub opts: RunOptions,
+    /// Name of the sandbox profile in `opts`, if there is one
+    pub sandbox_profile: Option<String>,
+    /// The files next to the script, if it's materialized. They're reviewed
+    /// together with the script.
+    pub siblings: Option<Vec<RepoFile>>,
+    pub review: ReviewState,
+    yes: bool,
+}
+
+impl ScriptSource {
+    /// Fetches the script and decides how it's run: checks its metadata's
+    /// requirements, merges the environment and picks the sandbox profile.
+    /// Nothing is run and the script isn't reviewed yet.
+    pub async fn plan_run(&self, config: &Config, request: RunRequest) -> Result<RunPlan<'_>> {
+        let script = self.fetch(config, request.fresh).await?;
+        ScriptMeta::parse(&String::from_utf8_lossy(&script.contents)).check_requirements()?;
+
+        // Later sources take precedence: repo defaults, env file, flags
+        let repo_opts = self.get_repo(config)?.options().clone();
+        let mut env = repo_opts.env;
+        if let Some(env_file) = &request.env_file {
+            env.extend(repo::parse_env_file(env_file).await?);
+        }
+
+        env.extend(request.env);
+        env.extend(self.provenance_env(&script.commit));
+
+        let sandbox_name = request.sandbox_profile.or(repo_opts.sandbox);
+        let use_sandbox = request.sandbox
+            || sandbox_name.is_some()
+            || request.no_network
+            || !request.bind.is_empty();
+
+        let (sandbox, sandbox_profile) = if use_sandbox && !request.no_sandbox {
+            let name = sandbox_name.unwrap_or_else(|| sandbox::DEFAULT_PROFILE.to_owned());
+            let mut profile = config.sandbox_profile(&name)?;
+            profile.network &= !request.no_network;
+            profile.bind.extend(request.bind);
+
+            // Makes sure the sandbox can be set up, without starting it
+            profile.command("bash")?;
+            (Some(profile), Some(name))
+        } else {
+            (None, None)
+        };
+
+        let siblings = match request.materialize {
+            true => Some(self.fetch_siblings(config, &script).await?),
+            false => None,
+        };
+
+        let files = reviewed_files(self, &script, siblings.as_deref());
+        let review = trust::review_state(&self.repo, &files).await?;
+
+        Ok(RunPlan {
+            src: self,
+            script,
+            args: request.args,
+            opts: RunOptions {
+                env,
+                clear_env: request.clear_env,
+                keep_env: request.keep_env,
+                cwd: request.cwd,
+                timeout: request.timeout,
+                grace_period: request.grace_period,
+                sandbox,
+            },
+            sandbox_profile,
+            siblings,
+            review,
+            yes: request.yes,
+        })
+    }
+}
+
+impl RunPlan<'_> {
+    /// Makes sure the script was reviewed, then runs it. Scripts that fail
+    /// are reported as `RemError::ScriptFailed`.
+    pub async fn run(&self, config: &Config) -> Result<()> {
+        let src = self.src;
+        let files = reviewed_files(src, &self.script, self.siblings.as_deref());
+        trust::review_script(&src.repo, &files, self.yes).await?;
+
+        let siblings = self.siblings.as_deref();
+        let exit = src
+            .run(config, &self.script, &self.args, &self.opts, siblings)
+            .await?;
+
+        if !exit.success() {
+            let code = exit
+                .code()
+                .unwrap_or_else(|| 128 + exit.signal().unwrap_or_default());
+
+            return Err(RemError::ScriptFailed(code).into());
+        }
+
+        Ok(())
+    }
+}
+
+/// Parses a GitHub or GitLab URL and checks that the repo can be accessed
+/// with the given credentials, so it can be saved
+pub async fn validate_api_repo(
+    uri: &str,
+    username: Option<String>,
+    password: Password,
+    app: Option<GithubApp>,
+    token_kind: Option<GitlabTokenKind>,
+    http: &HttpClient,
+) -> Result<Box<dyn Repo>> {
+    let mut maybe_parsed: Option<Url> = None;
+
+    // Check if we've been given a raw gitlab or github url without scheme
+    if uri.starts_with("gitlab.com") || uri.starts_with("github.com") {
+        let with_scheme = format!("https://{}", uri);
+        maybe_parsed = Some(Url::parse(&with_scheme)?);
+    }
+
+    // Try parsing the url manually otherwise
+    let mut parsed = match maybe_parsed {
+        Some(parsed) => parsed,
+        None => Url::parse(uri)?,
+    };
+
+    if parsed.cannot_be_a_base() {
+        return Err(RemError::ParseError("Repo URI was not recognized".to_owned()).into());
+    }
+
+    // Enforce https
+    let _ = parsed.set_scheme("https");
+
+    match parsed.host_str() {
+        Some("gitlab.com") if app.is_some() => {
+            bail!("GitHub Apps can only be used for github repos")
+        }
+        Some("gitlab.com") => Ok(gitlab::fetch_project(&parsed, password, token_kind, http).await?),
+        Some("github.com") if token_kind.is_some() => {
+            bail!("Token kinds can only be used for gitlab repos")
+        }
+        Some("github.com") => {
+            Ok(github::fetch_project(&parsed, username, password, app, http).await?)
+        }
+        Some(_) => {
+            Err(RemError::ParseError("No provider recognized for passed URI".to_owned()).into())
+        }
+        None => Err(RemError::ParseError("No host on passed URI".to_owned()).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(script: &str) -> ScriptSource {
+        ScriptSource::parse(script, ScriptAction::Run).unwrap()
+    }
+
+    #[test]
+    fn parses_saved_repo_refs() {
+        let src = parse("myscripts:hello.bash");
+        assert_eq!(src.source_type, SourceType::Saved);
+        assert_eq!(src.repo, "myscripts");
+        assert_eq!(src.rref, None);
+        assert_eq!(src.script_name, "hello.bash");
+
+        for rref in ["main", "feature/login", "v1.0", "release-1.2#3"] {
+            let src = parse(&format!("myscripts@{}:dir/hello.bash", rref));
+            assert_eq!(src.source_type, SourceType::Saved);
+            assert_eq!(src.repo, "myscripts");
+            assert_eq!(src.rref.as_deref(), Some(rref));
+            assert_eq!(src.script_name, "dir/hello.bash");
+        }
+    }
+
+    #[test]
+    fn parses_git_refs() {
+        let src = parse("git@github.com:org/repo.git:hello.bash");
+        assert_eq!(src.source_type, SourceType::Git);
+        assert_eq!(src.repo, "git@github.com:org/repo.git");
+        assert_eq!(src.rref, None);
+        assert_eq!(src.script_name, "hello.bash");
+
+        for rref in ["main", "feature/login", "v1.0"] {
+            let src = parse(&format!("git@github.com:org/repo.git@{}:hello.bash", rref));
+            assert_eq!(src.source_type, SourceType::Git);
+            assert_eq!(src.repo, "git@github.com:org/repo.git");
+            assert_eq!(src.rref.as_deref(), Some(rref));
+            assert_eq!(src.script_name, "hello.bash");
+
+            let src = parse(&format!("https://gitlab.com/org/repo@{}:hello.bash", rref));
+            assert_eq!(src.source_type, SourceType::Git);
+            assert_eq!(src.repo, "https://gitlab.com/org/repo");
+            assert_eq!(src.rref.as_deref(), Some(rref));
+        }
+    }
+
+    #[test]
+    fn parses_dirs_without_trailing_colon() {
+        let src = ScriptSource::parse_dir("git@github.com:org/repo@v1.0", ScriptAction::List);
+        let src = src.unwrap();
+        assert_eq!(src.source_type, SourceType::Git);
+        assert_eq!(src.repo, "git@github.com:org/repo");
+        assert_eq!(src.rref.as_deref(), Some("v1.0"));
+        assert_eq!(src.script_name, "");
+
+        let src = ScriptSource::parse_dir("myscripts@feature/login", ScriptAction::List);
+        let src = src.unwrap();
+        assert_eq!(src.source_type, SourceType::Saved);
+        assert_eq!(src.rref.as_deref(), Some("feature/login"));
+
+        let src = ScriptSource::parse_dir("myscripts:util", ScriptAction::List).unwrap();
+        assert_eq!(src.script_name, "util");
+    }
+
+    #[test]
+    fn rejects_invalid_refs() {
+        for rref in [
+            "/../../home/victim",
+            "a/../b",
+            "..",
+            ".hidden",
+            "a//b",
+            "a/",
+            "a.lock",
+            "a.",
+            "@",
+            "a@{1}",
+            "a b",
+            "a\\b",
+            "a~1",
+        ] {
+            let script = format!("git@github.com:org/repo@{}:x.sh", rref);
+            assert!(
+                ScriptSource::parse(&script, ScriptAction::Run).is_err(),
+                "{}",
+                rref
+            );
+
+            let script = format!("myscripts@{}:x.sh", rref);
+            assert!(
+                ScriptSource::parse(&script, ScriptAction::Run).is_err(),
+                "{}",
+                rref
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_sources_without_script() {
+        assert!(ScriptSource::parse("myscripts", ScriptAction::Run).is_err());
+        assert!(ScriptSource::parse("myscripts@v1.0", ScriptAction::Run).is_err());
+    }
+
+    #[test]
+    fn run_requests_default_to_the_cli_grace_period() {
+        let ten_seconds = Duration::from_secs(10);
+        assert_eq!(RunRequest::default().grace_period, ten_seconds);
+        assert_eq!(RunOptions::default().grace_period, ten_seconds);
+    }
+}
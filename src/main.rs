@@ -1,29 +1,28 @@
-use crate::{
-    config::{save_config, Config},
-    repo::Repo,
+use crate::output::{
+    DryRunOutput, EntryListOutput, EntryOutput, ErrorOutput, LogOutput, OutputFormat,
+    RepoListOutput, RepoOutput, SandboxOutput, ScriptInfoOutput, SearchOutput, SearchResultOutput,
 };
-use anyhow::{anyhow, bail, Context, Result};
+use anyhow::{bail, Context, Result};
 use clap::{AppSettings, Clap};
-use lazy_static::lazy_static;
-use regex::Regex;
+use rem_bash::{
+    audit::{self, AuditAction},
+    config::{self, save_config},
+    error::RemError,
+    github::GithubApp,
+    gitlab::GitlabTokenKind,
+    meta::ScriptMeta,
+    plugin, repo, report,
+    search::{SearchIndex, SearchResult},
+    trust::{self, escape_control, ReviewState},
+    validate_api_repo, Password, RunRequest, ScriptAction, ScriptSource, SourceType,
+};
 use std::env;
 use std::io::{self, Read};
-use url::Url;
-
-mod config;
-mod git;
-mod github;
-mod gitlab;
-mod repo;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
 
-lazy_static! {
-    static ref API_SOURCE_REGEX: Regex =
-        Regex::new(r"(?P<alias>^\w+)(@(?P<ref>\w+))?:(?P<script>.+)$").unwrap();
-
-    static ref GIT_SOURCE_REGEX: Regex =
-        Regex::new(r"^(?P<repo>((git|ssh|http(s)?)|(git@[\w\.]+))(:(//)?)([\w\./\-~]+)(\.git)?(/)?)(@(?P<ref>\w+))?:(?P<script>.+)$")
-            .unwrap();
-}
+mod output;
+mod picker;
 
 #[derive(Clap, Debug)]
 #[clap(author, about, version)]
@@ -31,11 +30,19 @@ lazy_static! {
 #[clap(setting = AppSettings::DeriveDisplayOrder)]
 #[clap(setting = AppSettings::SubcommandRequiredElseHelp)]
 struct Opts {
+    /// Output format. With `json`, errors are printed to stderr as JSON too.
+    #[clap(
+        long,
+        global = true,
+        default_value = "text",
+        possible_values = &["text", "json"]
+    )]
+    output: OutputFormat,
     #[clap(subcommand)]
     command: Command,
 }
 
-const SCRIPT_HELP: &'static str = r"Script identifier for a script from a repository
+const SCRIPT_HELP: &str = r"Script identifier for a script from a repository
 
     For saved repos: `<repo>[@ref]:<script_path>`
         Example: `myscripts:hello.bash`
@@ -46,6 +53,17 @@ const SCRIPT_HELP: &'static str = r"Script identifier for a script from a reposi
         Example (w/ ref): `git@github.com:user/myscripts@main:hello.bash`
 ";
 
+const LIST_HELP: &str = r"Repository to list scripts from, optionally followed by a directory
+
+    For saved repos: `<repo>[@ref][:<dir>]`
+        Example: `myscripts`
+        Example (w/ ref and dir): `myscripts@v1.0:util`
+
+    For git repos: `git@<repo_url>[@ref][:<dir>]`
+        Example: `git@github.com:user/myscripts`
+        Example (w/ ref and dir): `git@github.com:user/myscripts@main:util`
+";
+
 #[derive(Clap, Debug)]
 enum Command {
     /// Read and modify locally saved repositories
@@ -58,19 +76,109 @@ enum Command {
         /// Force a fresh download of the script (only for raw git repositories)
         #[clap(short, long)]
         fresh: bool,
+        /// Set an environment variable for the script (`KEY=VALUE`)
+        #[clap(short, long, number_of_values = 1, parse(try_from_str = repo::parse_env_pair))]
+        env: Vec<(String, String)>,
+        /// Read environment variables for the script from a file
+        #[clap(long)]
+        env_file: Option<PathBuf>,
+        /// Run the script with an empty environment, except for PATH, HOME, USER, TERM and LANG
+        #[clap(long)]
+        clear_env: bool,
+        /// Keep the given variable when clearing the environment
+        #[clap(long, number_of_values = 1, requires = "clear-env")]
+        keep_env: Vec<String>,
+        /// Working directory to run the script in
+        #[clap(long)]
+        cwd: Option<PathBuf>,
+        /// Run the script from a checkout of its directory, so sibling files can be used
+        #[clap(short, long)]
+        materialize: bool,
+        /// Stop the script if it's still running after the given duration (e.g. `30s`, `5m`)
+        #[clap(short, long, parse(try_from_str = humantime::parse_duration))]
+        timeout: Option<Duration>,
+        /// Time to wait after SIGTERM before killing a timed out script with SIGKILL
+        // Same as `process::DEFAULT_GRACE_PERIOD`
+        #[clap(long, default_value = "10s", parse(try_from_str = humantime::parse_duration))]
+        grace_period: Duration,
+        /// Run the script in a sandbox with a read-only root filesystem and a private /tmp
+        #[clap(short, long)]
+        sandbox: bool,
+        /// Sandbox profile from the config to use, implies `--sandbox`
+        #[clap(long)]
+        sandbox_profile: Option<String>,
+        /// Block network access inside the sandbox, implies `--sandbox`
+        #[clap(long)]
+        no_network: bool,
+        /// Keep the given path writable inside the sandbox, implies `--sandbox`
+        #[clap(long, number_of_values = 1)]
+        bind: Vec<PathBuf>,
+        /// Don't use the repo's default sandbox profile
+        #[clap(long, conflicts_with_all = &["sandbox", "sandbox-profile", "no-network", "bind"])]
+        no_sandbox: bool,
+        /// Run the script without reviewing it, even if it's new or changed
+        #[clap(short, long)]
+        yes: bool,
+        /// Print what would be run, without running the script
+        #[clap(long)]
+        dry_run: bool,
         #[clap(about = "Script to run", long_about = SCRIPT_HELP)]
-        script: String,
+        script: Option<String>,
         /// Args to be passed to the script
         #[clap(about = "Args to be passed to the script")]
         args: Vec<String>,
+        /// Args to be passed to the script when picking it interactively
+        #[clap(last = true)]
+        trailing_args: Vec<String>,
     },
     /// Import a script and print it to stdout
     Import {
         #[clap(short, long)]
         fresh: bool,
         #[clap(about = "Script to import", long_about = SCRIPT_HELP)]
+        script: Option<String>,
+    },
+    /// Show a script's metadata and where it was resolved from
+    Info {
+        #[clap(short, long)]
+        fresh: bool,
+        #[clap(about = "Script to show", long_about = SCRIPT_HELP)]
         script: String,
     },
+    /// List the scripts available in a repository
+    #[clap(alias = "ls")]
+    List {
+        /// Force a fresh download of the repository (only for raw git repositories)
+        #[clap(short, long)]
+        fresh: bool,
+        /// Include scripts in subdirectories
+        #[clap(short, long)]
+        recursive: bool,
+        /// Show all files, regardless of the configured script extensions
+        #[clap(short, long)]
+        all: bool,
+        #[clap(about = "Repository to list", long_about = LIST_HELP)]
+        source: String,
+    },
+    /// Search the scripts in all saved repositories by name and description
+    Search {
+        /// Rebuild the search index for all saved repositories
+        #[clap(short, long)]
+        refresh: bool,
+        /// Text to fuzzy match against script names and descriptions
+        query: String,
+    },
+    /// Show the scripts that were run or imported on this machine
+    Log {
+        /// Only show entries newer than the given duration (e.g. `1h`, `7d`)
+        #[clap(long, parse(try_from_str = humantime::parse_duration))]
+        since: Option<Duration>,
+        /// Show at most the given number of entries, starting with the most recent
+        #[clap(short = 'n', long)]
+        limit: Option<usize>,
+        /// Only show entries whose script (`<repo>@<ref>:<path>`) contains the given text
+        filter: Option<String>,
+    },
 }
 
 #[derive(Clap, Debug)]
@@ -97,6 +205,28 @@ enum RepoCommand {
         /// Reads the password or token from stdin
         #[clap(long)]
         password_stdin: bool,
+        /// Authenticates as the installation of the given GitHub App
+        #[clap(
+            long,
+            requires = "private-key",
+            conflicts_with_all = &["username", "password", "password-env", "password-stdin"]
+        )]
+        app_id: Option<u64>,
+        /// Private key (PEM) of the GitHub App, read whenever a new token is needed
+        #[clap(long, requires = "app-id")]
+        private_key: Option<PathBuf>,
+        /// How the GitLab token is sent. `job` uses `CI_JOB_TOKEN` if no token is given.
+        #[clap(long, possible_values = &["private", "job", "bearer"])]
+        token_kind: Option<GitlabTokenKind>,
+        /// Fetches scripts through the `rem-provider-<name>` executable on PATH
+        #[clap(
+            long,
+            conflicts_with_all = &["username", "password", "password-env", "password-stdin", "app-id", "token-kind"]
+        )]
+        plugin: Option<String>,
+        /// Setting passed to the plugin with every request (`KEY=VALUE`)
+        #[clap(long, number_of_values = 1, requires = "plugin", parse(try_from_str = repo::parse_env_pair))]
+        setting: Vec<(String, String)>,
     },
     /// Remove a repository from the local repository list
     #[clap(alias = "rm")]
@@ -106,21 +236,62 @@ enum RepoCommand {
     },
 }
 
-#[derive(PartialEq)]
-pub enum Password {
-    Saved(String),
-    FromEnv(String, String),
-    None,
+#[tokio::main]
+async fn main() {
+    let opts = Opts::parse();
+    let output = opts.output;
+    report::set_json(output == OutputFormat::Json);
+
+    if let Err(err) = run(opts).await {
+        let rem_error = err.downcast_ref::<RemError>();
+        let exit_code = rem_error.map_or(1, RemError::exit_code);
+
+        if output == OutputFormat::Json {
+            match serde_json::to_string(&ErrorOutput::new(&err)) {
+                Ok(json) => eprintln!("{}", json),
+                Err(_) => eprintln!("Error: {:?}", err),
+            }
+
+            std::process::exit(exit_code);
+        }
+
+        // Failed scripts already reported what went wrong themselves, only
+        // their exit code may need explaining
+        match rem_error {
+            Some(RemError::ScriptFailed(code)) if *code != exit_code => eprintln!(
+                "Script exited with code {}, which rem reserves for its own errors, exiting with {} instead",
+                code, exit_code
+            ),
+            Some(RemError::ScriptFailed(_)) => {}
+            _ => eprintln!("Error: {:?}", err),
+        }
+
+        if let Some(hint) = rem_error.and_then(RemError::hint) {
+            eprintln!("\nHint: {}", hint);
+        }
+
+        std::process::exit(exit_code);
+    }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+async fn run(opts: Opts) -> Result<()> {
     openssl_probe::init_ssl_cert_env_vars();
     let mut config = config::load_config().await?;
+    let json = opts.output == OutputFormat::Json;
 
-    match Opts::parse().command {
+    match opts.command {
         Command::Repo { command } => match command {
             RepoCommand::List => {
+                if json {
+                    let repos = config
+                        .repo
+                        .iter()
+                        .map(|(name, repo)| RepoOutput::new(Some(name), repo.as_ref()))
+                        .collect();
+
+                    return output::print_json(&RepoListOutput { repos });
+                }
+
                 if config.repo.is_empty() {
                     println!("No Saved repositories.");
                     return Ok(());
@@ -138,6 +309,11 @@ async fn main() -> Result<()> {
                 password,
                 password_env,
                 password_stdin,
+                app_id,
+                private_key,
+                token_kind,
+                plugin,
+                setting,
             } => {
                 if config.repo.contains_key(&name) {
                     bail!("A repository with the name `{}` already exists", &name);
@@ -149,204 +325,458 @@ async fn main() -> Result<()> {
                     (_, _, true) => {
                         let mut buf = String::new();
                         io::stdin().read_to_string(&mut buf)?;
-                        Password::Saved(buf)
+                        Password::Saved(buf.trim_end().to_owned())
                     }
                     _ => Password::None,
                 };
 
-                let repo = validate_api_repo(&uri, username, password_for_parse).await?;
+                let app = match (app_id, private_key) {
+                    (Some(app_id), Some(private_key)) => Some(GithubApp {
+                        app_id,
+                        private_key: private_key.canonicalize().with_context(|| {
+                            format!("Private key `{}` was not found", private_key.display())
+                        })?,
+                    }),
+                    _ => None,
+                };
+
+                let repo = match plugin {
+                    Some(plugin) => {
+                        plugin::fetch_project(&plugin, &uri, setting.into_iter().collect()).await?
+                    }
+                    None => {
+                        validate_api_repo(
+                            &uri,
+                            username,
+                            password_for_parse,
+                            app,
+                            token_kind,
+                            &config.http_client,
+                        )
+                        .await?
+                    }
+                };
                 config.repo.insert(name.clone(), repo);
                 save_config(&config)
                     .await
                     .context("Failed to save updated config")?;
 
+                if json {
+                    return output::print_json(&RepoOutput::new(
+                        Some(&name),
+                        config.repo[&name].as_ref(),
+                    ));
+                }
+
                 println!("Repo `{}` was successfully added", &name);
             }
             RepoCommand::Remove { name } => {
                 if !config.repo.contains_key(&name) {
-                    bail!("Repo `{}` was not found", &name);
+                    return Err(
+                        RemError::NotFound(format!("Repo `{}` was not found", &name)).into(),
+                    );
                 }
 
-                config.repo.remove(&name);
+                let repo = config.repo.remove(&name).unwrap();
                 save_config(&config)
                     .await
                     .context("Failed to save updated config")?;
 
+                if json {
+                    return output::print_json(&RepoOutput::new(Some(&name), repo.as_ref()));
+                }
+
                 println!("Repo `{}` was removed", &name);
             }
         },
         Command::Run {
             script,
-            args,
+            mut args,
+            trailing_args,
             fresh,
+            env,
+            env_file,
+            clear_env,
+            keep_env,
+            cwd,
+            materialize,
+            timeout,
+            grace_period,
+            sandbox,
+            sandbox_profile,
+            no_network,
+            bind,
+            no_sandbox,
+            yes,
+            dry_run,
         } => {
+            let script = match script {
+                Some(script) => script,
+                None => {
+                    let ext = &config.require_bash_extension;
+                    picker::pick_script(&config, |path| {
+                        ext.as_ref().is_none_or(|ext| path.ends_with(ext.as_str()))
+                    })
+                    .await?
+                }
+            };
+
+            args.extend(trailing_args);
             let src = ScriptSource::parse(&script, ScriptAction::Run)?;
             src.validate_script_name(&config)?;
 
-            let contents = src.fetch_script_contents(&config, fresh).await?;
-            let args = args.iter().map(|s| &**s).collect();
-
-            // TODO(happens): Find a way to propagate the actual exit code
-            // instead of simply returning 0/1 depending on the script.
-            // This should cover most use cases if you just want to know
-            // if the script failed, but until `std::process::Termination`
-            // is stabilized, it seems unsafe to use `std::process::exit`
-            // since we're using a tokio main.
-            let exit = repo::run_script(&contents, args).await?;
-            if !exit.success() {
-                bail!("");
+            let request = RunRequest {
+                args,
+                fresh,
+                env,
+                env_file,
+                clear_env,
+                keep_env,
+                cwd,
+                materialize,
+                timeout,
+                grace_period,
+                sandbox,
+                sandbox_profile,
+                no_network,
+                bind,
+                no_sandbox,
+                yes,
+            };
+
+            let plan = src.plan_run(&config, request).await?;
+            if !dry_run {
+                return plan.run(&config).await;
+            }
+
+            let repo = src.get_repo(&config)?;
+            let contents = &plan.script.contents;
+            let keep_env = plan.opts.clear_env.then(|| {
+                repo::DEFAULT_KEEP_ENV
+                    .iter()
+                    .copied()
+                    .chain(plan.opts.keep_env.iter().map(String::as_str))
+                    .collect::<Vec<_>>()
+            });
+
+            let siblings = plan
+                .siblings
+                .iter()
+                .flatten()
+                .map(|file| file.path.as_str())
+                .collect::<Vec<_>>();
+
+            if json {
+                let name = match src.source_type {
+                    SourceType::Saved => Some(src.repo.as_str()),
+                    SourceType::Git => None,
+                };
+
+                return output::print_json(&DryRunOutput {
+                    script: &src.script_name,
+                    repo: RepoOutput::new(name, repo.as_ref()),
+                    rref: src.get_ref(),
+                    commit: &plan.script.commit,
+                    sha256: trust::hash_script(contents),
+                    trust: plan.review.kind(),
+                    materialize: plan.siblings.is_some(),
+                    siblings,
+                    args: &plan.args,
+                    cwd: plan.opts.cwd.as_deref(),
+                    timeout_ms: plan.opts.timeout.map(|timeout| timeout.as_millis() as u64),
+                    sandbox: plan.opts.sandbox.as_ref().map(|profile| SandboxOutput {
+                        profile: plan.sandbox_profile.as_deref().unwrap_or_default(),
+                        network: profile.network,
+                        bind: &profile.bind,
+                    }),
+                    keep_env,
+                    env: &plan.opts.env,
+                    contents: &String::from_utf8_lossy(contents),
+                });
+            }
+
+            let trust = match plan.review {
+                ReviewState::Unchecked => "not checked (REM_TRUST=all)",
+                ReviewState::Trusted => "reviewed",
+                ReviewState::New => "not reviewed yet",
+                ReviewState::Changed => "changed since it was last reviewed",
+            };
+
+            let interpreter = if plan.siblings.is_some() {
+                "bash <script> (from a directory with the files next to it)"
+            } else {
+                "bash -c <contents> rem"
+            };
+
+            println!("Script:          {}", src.script_name);
+            println!("Repository:      {}", src.repo_name(repo.as_ref()));
+            println!("Ref:             {}", src.get_ref());
+            println!("Commit:          {}", plan.script.commit);
+            println!("SHA-256:         {}", trust::hash_script(contents));
+            println!("Trust:           {}", trust);
+            println!("Interpreter:     {}", interpreter);
+            println!("Arguments:       {:?}", plan.args);
+
+            for (i, sibling) in siblings.iter().enumerate() {
+                let label = if i == 0 { "Files:" } else { "" };
+                println!("{:<17}{}", label, sibling);
+            }
+
+            if let Some(cwd) = &plan.opts.cwd {
+                println!("Directory:       {}", cwd.display());
+            }
+
+            if let Some(timeout) = plan.opts.timeout {
+                println!("Timeout:         {}", humantime::format_duration(timeout));
             }
+
+            if let Some(profile) = &plan.opts.sandbox {
+                let name = plan.sandbox_profile.as_deref().unwrap_or_default();
+                let network = if profile.network {
+                    "allowed"
+                } else {
+                    "blocked"
+                };
+                println!("Sandbox:         {} (network {})", name, network);
+                for bind in &profile.bind {
+                    println!("                 writable: {}", bind.display());
+                }
+            }
+
+            if let Some(keep) = keep_env {
+                println!("Cleared env:     keeping {}", keep.join(", "));
+            }
+
+            println!("Environment:");
+            for (key, value) in &plan.opts.env {
+                println!("    {}={}", key, value);
+            }
+
+            println!("Contents:");
+            print!("{}", trust::display_contents(contents));
         }
         Command::Import { script, fresh } => {
+            // The script itself is printed, to be sourced by the shell
+            if json {
+                bail!("`import` prints the script itself and doesn't support `--output json`");
+            }
+
+            let script = match script {
+                Some(script) => script,
+                None => {
+                    let ext = &config.require_lib_extension;
+                    picker::pick_script(&config, |path| {
+                        ext.as_ref().is_none_or(|ext| path.ends_with(ext.as_str()))
+                    })
+                    .await?
+                }
+            };
+
             let src = ScriptSource::parse(&script, ScriptAction::Import)?;
             src.validate_script_name(&config)?;
 
-            let contents = src.fetch_script_contents(&config, fresh).await?;
-            repo::import_script(&contents).await?;
+            let fetched = src.fetch(&config, fresh).await?;
+            src.import(&config, &fetched).await?;
         }
-    };
+        Command::Info { script, fresh } => {
+            let src = ScriptSource::parse(&script, ScriptAction::Info)?;
+            src.validate_script_name(&config)?;
 
-    Ok(())
-}
+            let repo = src.get_repo(&config)?;
+            let fetched = src.fetch(&config, fresh).await?;
+            let (contents, commit) = (&fetched.contents, &fetched.commit);
+            let meta = ScriptMeta::parse(&String::from_utf8_lossy(contents));
 
-enum ScriptAction {
-    Run,
-    Import,
-}
+            if json {
+                let name = match src.source_type {
+                    SourceType::Saved => Some(src.repo.as_str()),
+                    SourceType::Git => None,
+                };
 
-pub struct ScriptSource {
-    repo: String,
-    source_type: SourceType,
-    script_name: String,
-    rref: Option<String>,
-    action: ScriptAction,
-}
+                return output::print_json(&ScriptInfoOutput {
+                    script: &src.script_name,
+                    repo: RepoOutput::new(name, repo.as_ref()),
+                    rref: src.get_ref(),
+                    commit,
+                    sha256: trust::hash_script(contents),
+                    description: meta.description.as_deref(),
+                    usage: meta.usage.as_deref(),
+                    args: &meta.args,
+                    requires_bins: &meta.requires_bins,
+                    min_rem_version: meta.min_rem_version.as_deref(),
+                });
+            }
 
-enum SourceType {
-    Git,
-    Saved,
-}
+            println!("Script:          {}", src.script_name);
+            println!("Repository:      {}", src.repo_name(repo.as_ref()));
+            println!("Ref:             {}", src.get_ref());
+            println!("Commit:          {}", commit);
 
-impl ScriptSource {
-    fn parse(script: &str, action: ScriptAction) -> Result<ScriptSource> {
-        if let Some(matches) = API_SOURCE_REGEX.captures(script) {
-            let repo = matches
-                .name("alias")
-                .expect("No alias matched")
-                .as_str()
-                .to_owned();
-
-            let script_name = matches
-                .name("script")
-                .expect("No script name matched")
-                .as_str()
-                .to_owned();
-
-            let rref = matches.name("ref").map(|rref| rref.as_str().to_owned());
-
-            return Ok(Self {
-                source_type: SourceType::Saved,
-                repo,
-                script_name,
-                rref,
-                action,
-            });
-        }
+            if let Some(description) = &meta.description {
+                println!(
+                    "Description:     {}",
+                    escape_control(&description.replace('\n', "\n                 "))
+                );
+            }
 
-        if let Some(matches) = GIT_SOURCE_REGEX.captures(script) {
-            let repo = matches
-                .name("repo")
-                .expect("No repo matched")
-                .as_str()
-                .to_owned();
-
-            let script_name = matches
-                .name("script")
-                .expect("No script name matched")
-                .as_str()
-                .to_owned();
-
-            let rref = matches.name("ref").map(|rref| rref.as_str().to_owned());
-
-            return Ok(Self {
-                source_type: SourceType::Git,
-                repo,
-                script_name,
-                rref,
-                action,
-            });
+            if let Some(usage) = &meta.usage {
+                println!(
+                    "Usage:           {}",
+                    escape_control(&usage.replace('\n', "\n                 "))
+                );
+            }
+
+            if !meta.args.is_empty() {
+                let args = meta.args.join("\n                 ");
+                println!("Arguments:       {}", escape_control(&args));
+            }
+
+            if !meta.requires_bins.is_empty() {
+                let bins = meta.requires_bins.join(", ");
+                println!("Requires:        {}", escape_control(&bins));
+            }
+
+            if let Some(min_version) = &meta.min_rem_version {
+                println!("Min rem version: {}", escape_control(min_version));
+            }
         }
+        Command::List {
+            fresh,
+            recursive,
+            all,
+            source,
+        } => {
+            // The directory is optional when listing, so we allow leaving out
+            // the trailing colon
+            let src = ScriptSource::parse_dir(&source, ScriptAction::List)?;
 
-        bail!("Script source could not be parsed")
-    }
+            let mut entries = src.list_scripts(&config, fresh, recursive).await?;
+            if !all {
+                entries.retain(|entry| entry.is_dir || config.is_script_name(&entry.path));
+            }
+
+            entries.sort();
+            if json {
+                let entries = entries
+                    .iter()
+                    .map(|entry| EntryOutput {
+                        path: &entry.path,
+                        is_dir: entry.is_dir,
+                    })
+                    .collect();
+
+                return output::print_json(&EntryListOutput { entries });
+            }
 
-    fn validate_script_name(&self, config: &Config) -> Result<()> {
-        if config.require_bash_extension.is_none() && config.require_lib_extension.is_none() {
-            return Ok(());
+            for entry in entries {
+                if entry.is_dir {
+                    println!("{}/", entry.path);
+                } else {
+                    println!("{}", entry.path);
+                }
+            }
         }
+        Command::Search { refresh, query } => {
+            let mut index = SearchIndex::load().await?;
+            index.update(&config, refresh).await?;
+            index.save().await?;
+
+            let results = index.search(&query);
+            if json {
+                let results = results
+                    .iter()
+                    .map(|result| SearchResultOutput {
+                        repo: result.repo,
+                        rref: result.rref,
+                        path: &result.entry.path,
+                        description: result.entry.description.as_deref(),
+                    })
+                    .collect();
+
+                return output::print_json(&SearchOutput { results });
+            }
 
-        let expected = match (
-            &config.require_bash_extension,
-            &config.require_lib_extension,
-            &self.action,
-        ) {
-            (Some(ref ext), _, &ScriptAction::Run) => ext,
-            (_, Some(ext), &ScriptAction::Import) => ext,
-            _ => unreachable!(),
-        };
-
-        if !self.script_name.ends_with(expected) {
-            bail!("Expected script name to end with `{}`", expected);
+            if results.is_empty() {
+                println!("No matching scripts found.");
+                return Ok(());
+            }
+
+            let names = results
+                .iter()
+                .map(SearchResult::script_name)
+                .collect::<Vec<_>>();
+
+            let width = names.iter().map(|name| name.len()).max().unwrap_or(0);
+            for (name, result) in names.iter().zip(&results) {
+                match &result.entry.description {
+                    // Multi-line descriptions are collapsed to keep one result per line
+                    Some(description) => println!(
+                        "{:width$}  {}",
+                        name,
+                        escape_control(&description.replace('\n', " ")),
+                        width = width
+                    ),
+                    None => println!("{}", name),
+                }
+            }
         }
+        Command::Log {
+            since,
+            limit,
+            filter,
+        } => {
+            let mut records = audit::read(config.audit_log.as_ref()).await?;
 
-        Ok(())
-    }
+            if let Some(since) = since {
+                let cutoff = SystemTime::now() - since;
+                records.retain(|record| record.started_at().is_some_and(|time| time >= cutoff));
+            }
 
-    async fn fetch_script_contents(&self, config: &config::Config, fresh: bool) -> Result<String> {
-        let repo = match self.source_type {
-            SourceType::Saved => config
-                .repo
-                .get(&self.repo)
-                .ok_or(anyhow!("Repo `{}` was not found", &self.repo))?
-                .box_clone(),
-            SourceType::Git => git::GitRepo::from_src(&self),
-        };
-
-        let rref = self.rref.clone().unwrap_or("HEAD".to_owned());
-        Ok(repo.fetch_script(&self.script_name, &rref, fresh).await?)
-    }
-}
+            if let Some(filter) = filter {
+                records.retain(|record| record.script_ref().contains(&filter));
+            }
 
-async fn validate_api_repo(
-    uri: &str,
-    username: Option<String>,
-    password: Password,
-) -> Result<Box<dyn Repo>> {
-    let mut maybe_parsed: Option<Url> = None;
-
-    // Check if we've been given a raw gitlab or github url without scheme
-    if uri.starts_with("gitlab.com") || uri.starts_with("github.com") {
-        let with_scheme = format!("https://{}", uri);
-        maybe_parsed = Some(Url::parse(&with_scheme)?);
-    }
+            if let Some(limit) = limit {
+                let skip = records.len().saturating_sub(limit);
+                records.drain(..skip);
+            }
 
-    // Try parsing the url manually otherwise
-    let mut parsed = match maybe_parsed {
-        Some(parsed) => parsed,
-        None => Url::parse(uri)?,
-    };
+            if json {
+                return output::print_json(&LogOutput { records: &records });
+            }
 
-    if parsed.cannot_be_a_base() {
-        bail!("Repo URI was not recognized");
-    }
+            if records.is_empty() {
+                println!("No matching entries found.");
+                return Ok(());
+            }
+
+            for record in records {
+                let exit_code = match (record.action, record.exit_code) {
+                    (AuditAction::Import, _) => "-".to_owned(),
+                    (AuditAction::Run, _) if !record.finished => "unfinished".to_owned(),
+                    (AuditAction::Run, Some(code)) => code.to_string(),
+                    (AuditAction::Run, None) => "killed".to_owned(),
+                };
 
-    // Enforce https
-    let _ = parsed.set_scheme("https");
+                let mut script = record.script_ref();
+                if !record.args.is_empty() {
+                    script = format!("{} {}", script, record.args.join(" "));
+                }
 
-    match parsed.host_str() {
-        Some("gitlab.com") => Ok(gitlab::fetch_project(&parsed, password).await?),
-        Some("github.com") => Ok(github::fetch_project(&parsed, username, password).await?),
-        Some(_) => bail!("No provider recognized for passed URI"),
-        None => bail!("No host on passed URI"),
-    }
+                println!(
+                    "{}  {}@{}  {:?}  {}",
+                    record.timestamp, record.user, record.host, record.action, script
+                );
+                println!(
+                    "    commit {}  sha256 {}  exit {}  took {}",
+                    record.commit,
+                    record.sha256,
+                    exit_code,
+                    humantime::format_duration(record.duration())
+                );
+            }
+        }
+    };
+
+    Ok(())
 }
@@ -0,0 +1,202 @@
+use anyhow::{bail, Result};
+use std::{env, path::Path};
+
+const META_PREFIX: &str = "rem:";
+
+/// Metadata parsed from the leading comment block of a script, e.g.:
+///
+/// ```bash
+/// #!/usr/bin/env bash
+/// # rem:description Uploads coverage results
+/// # rem:usage upload-results.sh <dir>
+/// # rem:args <dir>: Directory containing the results
+/// # rem:requires-bins curl jq
+/// # rem:min-rem-version 0.4.0
+/// ```
+#[derive(Default, Debug)]
+pub struct ScriptMeta {
+    pub description: Option<String>,
+    pub usage: Option<String>,
+    pub args: Vec<String>,
+    pub requires_bins: Vec<String>,
+    pub min_rem_version: Option<String>,
+}
+
+impl ScriptMeta {
+    pub fn parse(script: &str) -> Self {
+        let mut meta = Self::default();
+
+        let header = script
+            .lines()
+            .map(str::trim)
+            .take_while(|line| line.is_empty() || line.starts_with('#'));
+
+        for line in header {
+            let entry = match line
+                .trim_start_matches('#')
+                .trim()
+                .strip_prefix(META_PREFIX)
+            {
+                Some(entry) => entry,
+                None => continue,
+            };
+
+            let (key, value) = match entry.split_once(char::is_whitespace) {
+                Some((key, value)) => (key, value.trim()),
+                None => (entry, ""),
+            };
+
+            match key.trim_end_matches(':') {
+                "description" => append_line(&mut meta.description, value),
+                "usage" => append_line(&mut meta.usage, value),
+                "args" => meta.args.push(value.to_owned()),
+                "requires-bins" => meta
+                    .requires_bins
+                    .extend(value.split_whitespace().map(str::to_owned)),
+                "min-rem-version" => meta.min_rem_version = Some(value.to_owned()),
+                _ => {}
+            }
+        }
+
+        meta
+    }
+
+    /// Makes sure the current environment can run the script
+    pub fn check_requirements(&self) -> Result<()> {
+        if let Some(min_version) = &self.min_rem_version {
+            let current = env!("CARGO_PKG_VERSION");
+            if parse_version(current) < parse_version(min_version) {
+                bail!(
+                    "Script requires rem {} or newer, but this is rem {}",
+                    min_version,
+                    current
+                );
+            }
+        }
+
+        let missing = self
+            .requires_bins
+            .iter()
+            .filter(|bin| !is_on_path(bin))
+            .map(|bin| format!("`{}`", bin))
+            .collect::<Vec<_>>();
+
+        if !missing.is_empty() {
+            bail!("Script requires missing binaries: {}", missing.join(", "));
+        }
+
+        Ok(())
+    }
+}
+
+fn append_line(target: &mut Option<String>, value: &str) {
+    match target {
+        Some(existing) => {
+            existing.push('\n');
+            existing.push_str(value);
+        }
+        None => *target = Some(value.to_owned()),
+    }
+}
+
+/// Parses a dotted version into its numeric components, ignoring
+/// any pre-release or build suffix
+fn parse_version(version: &str) -> Vec<u64> {
+    version
+        .trim_start_matches('v')
+        .split(['-', '+'])
+        .next()
+        .unwrap_or_default()
+        .split('.')
+        .map(|part| part.parse().unwrap_or(0))
+        .collect()
+}
+
+pub fn is_on_path(bin: &str) -> bool {
+    if bin.contains('/') {
+        return Path::new(bin).is_file();
+    }
+
+    let path = match env::var_os("PATH") {
+        Some(path) => path,
+        None => return false,
+    };
+
+    env::split_paths(&path).any(|dir| dir.join(bin).is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_header_block() {
+        let script = "#!/usr/bin/env bash\n\
+            # rem:description Uploads coverage results\n\
+            #   rem:description to the dashboard\n\
+            \n\
+            # rem:usage: upload-results.sh <dir>\n\
+            # rem:args <dir>: Directory containing the results\n\
+            # rem:args [--dry-run]: Only print what would be uploaded\n\
+            # rem:requires-bins curl  jq\n\
+            # rem:requires-bins gzip\n\
+            # rem:min-rem-version 0.4.0\n\
+            echo hi\n";
+
+        let meta = ScriptMeta::parse(script);
+        assert_eq!(
+            meta.description.as_deref(),
+            Some("Uploads coverage results\nto the dashboard")
+        );
+        assert_eq!(meta.usage.as_deref(), Some("upload-results.sh <dir>"));
+        assert_eq!(
+            meta.args,
+            [
+                "<dir>: Directory containing the results",
+                "[--dry-run]: Only print what would be uploaded"
+            ]
+        );
+        assert_eq!(meta.requires_bins, ["curl", "jq", "gzip"]);
+        assert_eq!(meta.min_rem_version.as_deref(), Some("0.4.0"));
+    }
+
+    #[test]
+    fn stops_at_first_code_line() {
+        let script = "#!/bin/bash\n\
+            # rem:description Before the code\n\
+            set -e\n\
+            # rem:usage after-the-code.sh\n\
+            # rem:requires-bins curl\n";
+
+        let meta = ScriptMeta::parse(script);
+        assert_eq!(meta.description.as_deref(), Some("Before the code"));
+        assert_eq!(meta.usage, None);
+        assert!(meta.requires_bins.is_empty());
+    }
+
+    #[test]
+    fn ignores_unknown_keys_and_other_comments() {
+        let script = "# Deploys the app\n\
+            # rem:owner platform-team\n\
+            # rem:description Deploys\n\
+            # rem:\n";
+
+        let meta = ScriptMeta::parse(script);
+        assert_eq!(meta.description.as_deref(), Some("Deploys"));
+        assert_eq!(meta.usage, None);
+        assert!(meta.args.is_empty());
+    }
+
+    #[test]
+    fn compares_versions_numerically() {
+        assert_eq!(parse_version("0.4.0"), [0, 4, 0]);
+        assert_eq!(parse_version("v1.2"), [1, 2]);
+        assert_eq!(parse_version("1.2.3-beta.1+build"), [1, 2, 3]);
+        assert_eq!(parse_version("1.x"), [1, 0]);
+
+        assert!(parse_version("0.10.0") > parse_version("0.9.9"));
+        assert!(parse_version("1.0") < parse_version("1.0.1"));
+        assert!(parse_version("v2") > parse_version("1.99.99"));
+        assert_eq!(parse_version("1.0.0-rc.1"), parse_version("1.0.0"));
+    }
+}
@@ -0,0 +1,180 @@
+use anyhow::{bail, Context, Result};
+use rem_bash::{audit::AuditRecord, error::RemError, repo::Repo};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// What commands print. JSON output is meant for tools wrapping `rem`, so
+/// fields are only ever added to it, never renamed or removed.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => bail!("Unknown output format `{}`", s),
+        }
+    }
+}
+
+/// A repository, as printed by `repo ls`, `repo add`, `repo rm` and `info`
+#[derive(Serialize)]
+pub struct RepoOutput<'a> {
+    /// Alias of the saved repo, `None` for raw git sources
+    pub name: Option<&'a str>,
+    pub provider: &'static str,
+    pub url: String,
+    pub host: String,
+    pub path: String,
+}
+
+impl<'a> RepoOutput<'a> {
+    pub fn new(name: Option<&'a str>, repo: &dyn Repo) -> Self {
+        let location = repo.location();
+        Self {
+            name,
+            provider: repo.provider(),
+            url: repo.readable(),
+            host: location.host,
+            path: location.path,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct RepoListOutput<'a> {
+    pub repos: Vec<RepoOutput<'a>>,
+}
+
+/// A script's metadata and where it was resolved from, as printed by `info`
+#[derive(Serialize)]
+pub struct ScriptInfoOutput<'a> {
+    pub script: &'a str,
+    pub repo: RepoOutput<'a>,
+    #[serde(rename = "ref")]
+    pub rref: String,
+    pub commit: &'a str,
+    pub sha256: String,
+    pub description: Option<&'a str>,
+    pub usage: Option<&'a str>,
+    pub args: &'a [String],
+    pub requires_bins: &'a [String],
+    pub min_rem_version: Option<&'a str>,
+}
+
+/// A file or directory, as printed by `ls`
+#[derive(Serialize)]
+pub struct EntryOutput<'a> {
+    pub path: &'a str,
+    pub is_dir: bool,
+}
+
+#[derive(Serialize)]
+pub struct EntryListOutput<'a> {
+    pub entries: Vec<EntryOutput<'a>>,
+}
+
+/// A matching script, as printed by `search`, best matches first
+#[derive(Serialize)]
+pub struct SearchResultOutput<'a> {
+    pub repo: &'a str,
+    /// The ref the script was indexed at
+    #[serde(rename = "ref")]
+    pub rref: &'a str,
+    pub path: &'a str,
+    pub description: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+pub struct SearchOutput<'a> {
+    pub results: Vec<SearchResultOutput<'a>>,
+}
+
+/// The audit log, as printed by `log`, oldest records first
+#[derive(Serialize)]
+pub struct LogOutput<'a> {
+    pub records: &'a [AuditRecord],
+}
+
+/// What a run would do, as printed by `run --dry-run`
+#[derive(Serialize)]
+pub struct DryRunOutput<'a> {
+    pub script: &'a str,
+    pub repo: RepoOutput<'a>,
+    #[serde(rename = "ref")]
+    pub rref: String,
+    pub commit: &'a str,
+    pub sha256: String,
+    /// One of `trusted`, `new`, `changed` or `unchecked` with `REM_TRUST=all`
+    pub trust: &'static str,
+    pub materialize: bool,
+    /// Files run next to the script when it's materialized
+    pub siblings: Vec<&'a str>,
+    pub args: &'a [String],
+    pub cwd: Option<&'a Path>,
+    pub timeout_ms: Option<u64>,
+    pub sandbox: Option<SandboxOutput<'a>>,
+    /// The variables that are kept if the environment is cleared
+    pub keep_env: Option<Vec<&'a str>>,
+    pub env: &'a BTreeMap<String, String>,
+    pub contents: &'a str,
+}
+
+#[derive(Serialize)]
+pub struct SandboxOutput<'a> {
+    pub profile: &'a str,
+    pub network: bool,
+    pub bind: &'a [PathBuf],
+}
+
+/// An error, as printed to stderr by all commands
+#[derive(Serialize)]
+pub struct ErrorOutput {
+    pub error: ErrorDetails,
+}
+
+#[derive(Serialize)]
+pub struct ErrorDetails {
+    /// One of the `RemError` kinds, or `other`
+    pub kind: &'static str,
+    /// The error and its causes, separated by `: `
+    pub message: String,
+    pub hint: Option<&'static str>,
+    pub exit_code: i32,
+    /// The code a failed script exited with, which may differ from
+    /// `exit_code` if rem reserves it
+    pub script_exit_code: Option<i32>,
+}
+
+impl ErrorOutput {
+    pub fn new(err: &anyhow::Error) -> Self {
+        let rem_error = err.downcast_ref::<RemError>();
+        Self {
+            error: ErrorDetails {
+                kind: rem_error.map_or("other", RemError::kind),
+                message: format!("{:#}", err),
+                hint: rem_error.and_then(RemError::hint),
+                exit_code: rem_error.map_or(1, RemError::exit_code),
+                script_exit_code: rem_error.and_then(RemError::script_exit_code),
+            },
+        }
+    }
+}
+
+/// Prints a value as a single line of JSON
+pub fn print_json(value: &impl Serialize) -> Result<()> {
+    let json = serde_json::to_string(value).context("Failed to serialize output")?;
+    println!("{}", json);
+    Ok(())
+}
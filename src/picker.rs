@@ -0,0 +1,191 @@
+use anyhow::{anyhow, bail, Result};
+use crossterm::{
+    cursor,
+    event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    execute, queue,
+    style::{Attribute, Print, SetAttribute},
+    terminal::{self, ClearType},
+};
+use rem_bash::{
+    config::Config,
+    search::{SearchIndex, SearchResult},
+    trust::{escape_control, is_interactive},
+};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    io::{self, Stderr, Write},
+};
+
+/// Restores the terminal when the picker is closed, even on errors
+struct TerminalGuard(Stderr);
+
+impl TerminalGuard {
+    fn enter() -> Result<Self> {
+        let mut out = io::stderr();
+        terminal::enable_raw_mode()?;
+        execute!(out, terminal::EnterAlternateScreen)?;
+        Ok(Self(out))
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = execute!(self.0, terminal::LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// Opens a fuzzy finder over the scripts in all saved repositories and
+/// returns the chosen script as `<repo>:<path>`. Only scripts whose path
+/// passes `filter` are shown. The picker draws on stderr, so it can be used
+/// with `rem import` while stdout is being redirected.
+pub async fn pick_script(config: &Config, filter: impl Fn(&str) -> bool) -> Result<String> {
+    if !is_interactive() {
+        bail!("No script was given");
+    }
+
+    if config.repo.is_empty() {
+        bail!("No script was given and there are no saved repositories to pick from");
+    }
+
+    let mut index = SearchIndex::load().await?;
+    index.update(config, false).await?;
+    index.save().await?;
+
+    let mut previews = HashMap::new();
+    let mut query = String::new();
+    let mut selected = 0;
+
+    let mut guard = TerminalGuard::enter()?;
+    loop {
+        let matches = index
+            .search(&query)
+            .into_iter()
+            .filter(|result| filter(&result.entry.path))
+            .collect::<Vec<_>>();
+
+        selected = selected.min(matches.len().saturating_sub(1));
+
+        let current = matches.get(selected);
+        if let Some(result) = current {
+            if let Entry::Vacant(entry) = previews.entry(result.script_name()) {
+                entry.insert(fetch_preview(config, result).await);
+            }
+        }
+
+        let preview = current
+            .and_then(|result| previews.get(&result.script_name()))
+            .map(String::as_str)
+            .unwrap_or_default();
+
+        render(&mut guard.0, &query, &matches, selected, preview)?;
+
+        let key = match event::read()? {
+            Event::Key(key) if key.kind != KeyEventKind::Release => key,
+            _ => continue,
+        };
+
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match (key.code, ctrl) {
+            (KeyCode::Esc, _) | (KeyCode::Char('c'), true) => break,
+            (KeyCode::Enter, _) => {
+                return current
+                    .map(SearchResult::script_name)
+                    .ok_or(anyhow!("No script was selected"))
+            }
+            (KeyCode::Up, _) | (KeyCode::Char('p'), true) => selected = selected.saturating_sub(1),
+            (KeyCode::Down, _) | (KeyCode::Char('n'), true) => selected += 1,
+            (KeyCode::Backspace, _) => {
+                query.pop();
+                selected = 0;
+            }
+            (KeyCode::Char(c), false) => {
+                query.push(c);
+                selected = 0;
+            }
+            _ => {}
+        }
+    }
+
+    bail!("No script was selected")
+}
+
+async fn fetch_preview(config: &Config, result: &SearchResult<'_>) -> String {
+    let repo = match config.repo.get(result.repo) {
+        Some(repo) => repo,
+        None => return format!("Repo `{}` was not found", result.repo),
+    };
+
+    let location = repo.location();
+    if let Err(err) = config.check_policy(Some(result.repo), &location, result.rref) {
+        return format!("{:#}", err);
+    }
+
+    let cache = config.cache_policy(repo.as_ref(), false);
+    match repo
+        .fetch_script(&result.entry.path, result.rref, cache)
+        .await
+    {
+        Ok(contents) => contents,
+        Err(err) => format!("Failed to fetch script: {:#}", err),
+    }
+}
+
+fn truncate(text: &str, width: usize) -> String {
+    escape_control(&text.replace('\t', "    "))
+        .chars()
+        .take(width)
+        .collect()
+}
+
+/// Draws the query line, the list of matches on the left and a preview
+/// of the selected script on the right
+fn render(
+    out: &mut impl Write,
+    query: &str,
+    matches: &[SearchResult],
+    selected: usize,
+    preview: &str,
+) -> Result<()> {
+    let (width, height) = terminal::size()?;
+    let list_width = (width / 2).max(20);
+    let preview_width = width.saturating_sub(list_width + 2) as usize;
+    let rows = height.saturating_sub(1) as usize;
+    let offset = (selected + 1).saturating_sub(rows);
+    let mut preview_lines = preview.lines();
+
+    queue!(
+        out,
+        terminal::Clear(ClearType::All),
+        cursor::MoveTo(0, 0),
+        Print(format!("> {}", query))
+    )?;
+
+    for row in 0..rows {
+        let y = row as u16 + 1;
+        queue!(out, cursor::MoveTo(0, y))?;
+
+        if let Some(result) = matches.get(offset + row) {
+            let name = truncate(&result.script_name(), list_width as usize - 1);
+            if offset + row == selected {
+                queue!(
+                    out,
+                    SetAttribute(Attribute::Reverse),
+                    Print(name),
+                    SetAttribute(Attribute::Reset)
+                )?;
+            } else {
+                queue!(out, Print(name))?;
+            }
+        }
+
+        queue!(out, cursor::MoveTo(list_width, y), Print('│'))?;
+        if let Some(line) = preview_lines.next() {
+            queue!(out, Print(' '), Print(truncate(line, preview_width)))?;
+        }
+    }
+
+    queue!(out, cursor::MoveTo(query.chars().count() as u16 + 2, 0))?;
+    out.flush()?;
+    Ok(())
+}
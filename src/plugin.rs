@@ -0,0 +1,570 @@
+use crate::{
+    error::RemError,
+    repo::{order_refs, CachePolicy, Repo, RepoEntry, RepoLocation, RepoOptions},
+};
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::BTreeMap as Map, error::Error, fmt, io, path::PathBuf, process::Stdio,
+    time::Duration,
+};
+use tokio::{io::AsyncWriteExt, process::Command, time};
+use url::Url;
+
+pub const PROVIDER: &str = "plugin";
+
+/// Plugins are executables on `PATH` named after the plugin with this prefix
+pub const PLUGIN_PREFIX: &str = "rem-provider-";
+
+/// Sent with every request, so plugins can reject requests they don't understand
+const PROTOCOL_VERSION: u32 = 1;
+
+/// Seconds a plugin may take to answer a request, unless the repo sets `timeout`
+const DEFAULT_TIMEOUT: u64 = 60;
+
+/// A repo whose files are fetched by an external `rem-provider-<plugin>`
+/// executable. Each call runs the plugin once, with a JSON request on its
+/// stdin and a JSON response expected on its stdout.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PluginRepo {
+    plugin: String,
+    url: String,
+
+    /// Passed to the plugin with every request, e.g. the name of a
+    /// variable holding a token
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    settings: Map<String, String>,
+
+    /// Seconds the plugin may take to answer a request, after which it's
+    /// killed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timeout: Option<u64>,
+
+    #[serde(flatten)]
+    options: RepoOptions,
+
+    /// Runs this executable instead of looking up the plugin on `PATH`
+    #[serde(skip)]
+    command: Option<PathBuf>,
+}
+
+#[derive(Serialize)]
+struct PluginRequest<'a> {
+    version: u32,
+    #[serde(flatten)]
+    action: PluginAction<'a>,
+    url: &'a str,
+    settings: &'a Map<String, String>,
+    /// Set when the user asked for a fresh download, so plugins with their
+    /// own cache should skip it
+    fresh: bool,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+enum PluginAction<'a> {
+    Validate,
+    Fetch {
+        path: &'a str,
+        #[serde(rename = "ref")]
+        rref: &'a str,
+    },
+    List {
+        dir: &'a str,
+        #[serde(rename = "ref")]
+        rref: &'a str,
+        recursive: bool,
+    },
+    Resolve {
+        #[serde(rename = "ref")]
+        rref: &'a str,
+    },
+    Refs,
+}
+
+/// An error reported by a plugin. Kinds that match a `RemError` are
+/// turned into one, others like `unsupported` are kept as is.
+#[derive(Deserialize, Debug)]
+struct PluginError {
+    kind: String,
+    message: String,
+}
+
+impl fmt::Display for PluginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for PluginError {}
+
+#[derive(Deserialize)]
+struct ValidateResponse {}
+
+#[derive(Deserialize)]
+struct FetchResponse {
+    /// Base64 encoded file contents
+    content: String,
+}
+
+#[derive(Deserialize)]
+struct ListResponse {
+    entries: Vec<PluginEntry>,
+}
+
+#[derive(Deserialize)]
+struct RefsResponse {
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    branches: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct PluginEntry {
+    path: String,
+    #[serde(default)]
+    is_dir: bool,
+}
+
+#[derive(Deserialize)]
+struct ResolveResponse {
+    commit: String,
+}
+
+#[async_trait]
+#[typetag::serde]
+impl Repo for PluginRepo {
+    fn provider(&self) -> &'static str {
+        PROVIDER
+    }
+
+    fn readable(&self) -> String {
+        format!("{} via {}{}", self.url, PLUGIN_PREFIX, self.plugin)
+    }
+
+    fn location(&self) -> RepoLocation {
+        match Url::parse(&self.url) {
+            Ok(url) => RepoLocation {
+                host: url.host_str().unwrap_or_default().to_owned(),
+                path: url.path().trim_matches('/').to_owned(),
+            },
+            Err(_) => RepoLocation {
+                host: String::new(),
+                path: self.url.clone(),
+            },
+        }
+    }
+
+    fn options(&self) -> &RepoOptions {
+        &self.options
+    }
+
+    fn box_clone(&self) -> Box<dyn Repo> {
+        Box::new(self.clone())
+    }
+
+    async fn fetch_bytes(&self, path: &str, repo_ref: &str, cache: CachePolicy) -> Result<Vec<u8>> {
+        let action = PluginAction::Fetch {
+            path,
+            rref: repo_ref,
+        };
+
+        let resp: FetchResponse = self.request(action, cache).await?;
+        base64::decode(resp.content).map_err(|err| self.invalid_response(err.to_string()).into())
+    }
+
+    async fn list_scripts(
+        &self,
+        dir: &str,
+        repo_ref: &str,
+        recursive: bool,
+        cache: CachePolicy,
+    ) -> Result<Vec<RepoEntry>> {
+        let action = PluginAction::List {
+            dir,
+            rref: repo_ref,
+            recursive,
+        };
+
+        let resp: ListResponse = self.request(action, cache).await?;
+        Ok(resp
+            .entries
+            .into_iter()
+            .map(|entry| RepoEntry {
+                path: entry.path,
+                is_dir: entry.is_dir,
+            })
+            .collect())
+    }
+
+    async fn resolve_commit(&self, repo_ref: &str, cache: CachePolicy) -> Result<String> {
+        let action = PluginAction::Resolve { rref: repo_ref };
+        match self.request::<ResolveResponse>(action, cache).await {
+            Ok(resp) => Ok(resp.commit),
+            // Not every backend has versions, so the ref is all we know
+            Err(err) if is_unsupported(&err) => Ok(repo_ref.to_owned()),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn list_refs(&self, cache: CachePolicy) -> Result<Vec<String>> {
+        match self
+            .request::<RefsResponse>(PluginAction::Refs, cache)
+            .await
+        {
+            Ok(resp) => Ok(order_refs(resp.tags, resp.branches)),
+            // Without versions, `HEAD` is the only ref there is
+            Err(err) if is_unsupported(&err) => Ok(Vec::new()),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn checkout(&self, _repo_ref: &str, _cache: CachePolicy) -> Result<Option<PathBuf>> {
+        Ok(None)
+    }
+}
+
+impl PluginRepo {
+    fn executable(&self) -> String {
+        format!("{}{}", PLUGIN_PREFIX, self.plugin)
+    }
+
+    fn invalid_response(&self, reason: String) -> RemError {
+        RemError::ParseError(format!(
+            "Invalid response from `{}`: {}",
+            self.executable(),
+            reason
+        ))
+    }
+
+    /// Runs the plugin with a single request and parses its response.
+    /// Errors reported by the plugin are turned into `RemError`s of the
+    /// same kind.
+    async fn request<T: DeserializeOwned>(
+        &self,
+        action: PluginAction<'_>,
+        cache: CachePolicy,
+    ) -> Result<T> {
+        let request = serde_json::to_vec(&PluginRequest {
+            version: PROTOCOL_VERSION,
+            action,
+            url: &self.url,
+            settings: &self.settings,
+            fresh: cache == CachePolicy::Fresh,
+        })
+        .context("Failed to serialize plugin request")?;
+
+        // Names come from saved configs as well, which may have been edited
+        validate_name(&self.plugin)?;
+        let executable = self.executable();
+        let mut child = Command::new(self.command.as_deref().unwrap_or(executable.as_ref()))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|err| match err.kind() {
+                io::ErrorKind::NotFound => anyhow!(
+                    "Plugin `{}` was not found, make sure it's installed and on PATH",
+                    executable
+                ),
+                _ => anyhow::Error::new(err).context(format!("Failed to run `{}`", executable)),
+            })?;
+
+        // Writing and reading at the same time, so plugins that respond
+        // before reading the whole request can't block us. Failing to write
+        // only matters if the plugin didn't respond, which is caught below.
+        let mut stdin = child.stdin.take().expect("Plugin stdin was not piped");
+        let write = async move {
+            let _ = stdin.write_all(&request).await;
+        };
+
+        // Dropping the child on timeout kills it
+        let timeout = Duration::from_secs(self.timeout.unwrap_or(DEFAULT_TIMEOUT));
+        let output = match time::timeout(timeout, async {
+            tokio::join!(write, child.wait_with_output()).1
+        })
+        .await
+        {
+            Ok(output) => output.with_context(|| format!("Failed to run `{}`", executable))?,
+            Err(_) => {
+                return Err(RemError::Network(format!(
+                    "Plugin `{}` didn't respond within {}",
+                    executable,
+                    humantime::format_duration(timeout)
+                ))
+                .into())
+            }
+        };
+
+        let response: serde_json::Value = match serde_json::from_slice(&output.stdout) {
+            Ok(response) => response,
+            Err(_) if !output.status.success() => {
+                bail!("Plugin `{}` failed ({})", executable, output.status)
+            }
+            Err(err) => return Err(self.invalid_response(err.to_string()).into()),
+        };
+
+        if let Some(error) = response.get("error") {
+            let mut error = PluginError::deserialize(error)
+                .map_err(|err| self.invalid_response(err.to_string()))?;
+
+            error.message = format!("{} (from `{}`)", error.message, executable);
+            return Err(
+                match RemError::from_kind(&error.kind, error.message.clone()) {
+                    Some(rem_error) => rem_error.into(),
+                    None => error.into(),
+                },
+            );
+        }
+
+        T::deserialize(response).map_err(|err| self.invalid_response(err.to_string()).into())
+    }
+}
+
+fn is_unsupported(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<PluginError>()
+        .is_some_and(|err| err.kind == "unsupported")
+}
+
+/// Plugin names become part of the executable's name, so anything that
+/// could turn it into a path is rejected
+fn validate_name(plugin: &str) -> Result<()> {
+    let valid = plugin
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    if plugin.is_empty() || !valid {
+        return Err(RemError::ParseError(format!(
+            "Invalid plugin name `{}`, only letters, digits, `-` and `_` are allowed",
+            plugin
+        ))
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Creates a plugin repo and checks with the plugin that it can be accessed
+pub async fn fetch_project(
+    plugin: &str,
+    url: &str,
+    settings: Map<String, String>,
+) -> Result<Box<dyn Repo>> {
+    validate_name(plugin)?;
+
+    let repo = PluginRepo {
+        plugin: plugin.to_owned(),
+        url: url.to_owned(),
+        settings,
+        timeout: None,
+        options: RepoOptions::default(),
+        command: None,
+    };
+
+    repo.request::<ValidateResponse>(PluginAction::Validate, CachePolicy::Fresh)
+        .await?;
+
+    Ok(Box::new(repo))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+    use std::{env, fs, path::Path};
+
+    /// Returns a repo using the fixture plugin, which answers every request
+    /// with the given response and saves the request next to it
+    fn test_repo(name: &str, response: Value) -> (PluginRepo, PathBuf) {
+        test_repo_with_plugin(name, response, "rem-provider-fixture")
+    }
+
+    fn test_repo_with_plugin(name: &str, response: Value, fixture: &str) -> (PluginRepo, PathBuf) {
+        let dir = env::temp_dir().join(format!("rem-plugin-test-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("response.json"), response.to_string()).unwrap();
+
+        let repo = PluginRepo {
+            plugin: "fixture".to_owned(),
+            url: dir.to_str().unwrap().to_owned(),
+            settings: Map::from([("token_env".to_owned(), "STORE_TOKEN".to_owned())]),
+            timeout: None,
+            options: RepoOptions::default(),
+            command: Some(
+                PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                    .join("tests/fixtures")
+                    .join(fixture),
+            ),
+        };
+
+        (repo, dir)
+    }
+
+    fn sent_request(dir: &Path) -> Value {
+        serde_json::from_slice(&fs::read(dir.join("request.json")).unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn fetches_files_through_plugin() {
+        let contents = b"#!/bin/bash\n# caf\xe9\nexit 0\n".to_vec();
+        let (repo, dir) = test_repo("fetch", json!({ "content": base64::encode(&contents) }));
+
+        let fetched = repo
+            .fetch_bytes("bin/deploy.sh", "v1", CachePolicy::Fresh)
+            .await
+            .unwrap();
+
+        assert_eq!(fetched, contents);
+        assert_eq!(
+            sent_request(&dir),
+            json!({
+                "version": 1,
+                "action": "fetch",
+                "path": "bin/deploy.sh",
+                "ref": "v1",
+                "url": dir.to_str().unwrap(),
+                "settings": { "token_env": "STORE_TOKEN" },
+                "fresh": true,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn lists_files_through_plugin() {
+        let response = json!({
+            "entries": [
+                { "path": "util", "is_dir": true },
+                { "path": "util/lib.sh" },
+            ]
+        });
+
+        let (repo, dir) = test_repo("list", response);
+        let entries = repo
+            .list_scripts("util", "HEAD", true, CachePolicy::Cached)
+            .await
+            .unwrap();
+
+        let expected = vec![
+            RepoEntry {
+                path: "util".to_owned(),
+                is_dir: true,
+            },
+            RepoEntry {
+                path: "util/lib.sh".to_owned(),
+                is_dir: false,
+            },
+        ];
+
+        assert_eq!(entries, expected);
+
+        let request = sent_request(&dir);
+        assert_eq!(request["action"], "list");
+        assert_eq!(request["recursive"], true);
+        assert_eq!(request["fresh"], false);
+    }
+
+    #[tokio::test]
+    async fn reports_plugin_errors_by_kind() {
+        let error = json!({ "error": { "kind": "unauthorized", "message": "Token expired" } });
+        let (repo, _) = test_repo("error", error);
+
+        let err = repo
+            .fetch_bytes("deploy.sh", "HEAD", CachePolicy::Cached)
+            .await
+            .unwrap_err();
+
+        assert!(
+            matches!(err.downcast_ref(), Some(RemError::Unauthorized(_))),
+            "{}",
+            err
+        );
+        assert_eq!(
+            err.to_string(),
+            "Token expired (from `rem-provider-fixture`)"
+        );
+    }
+
+    #[tokio::test]
+    async fn uses_ref_as_commit_if_resolving_is_unsupported() {
+        let error = json!({ "error": { "kind": "unsupported", "message": "No versions" } });
+        let (repo, _) = test_repo("resolve", error);
+
+        let commit = repo
+            .resolve_commit("v1", CachePolicy::Cached)
+            .await
+            .unwrap();
+
+        assert_eq!(commit, "v1");
+    }
+
+    #[tokio::test]
+    async fn lists_refs_through_plugin() {
+        let response = json!({ "tags": ["v1.2", "v1.10"], "branches": ["main"] });
+        let (repo, dir) = test_repo("refs", response);
+
+        let refs = repo.list_refs(CachePolicy::Cached).await.unwrap();
+        assert_eq!(refs, ["v1.10", "v1.2", "main"]);
+        assert_eq!(sent_request(&dir)["action"], "refs");
+
+        let error = json!({ "error": { "kind": "unsupported", "message": "No versions" } });
+        let (repo, _) = test_repo("refs-unsupported", error);
+        assert!(repo
+            .list_refs(CachePolicy::Cached)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_responses() {
+        let (repo, _) = test_repo("invalid", json!({ "files": [] }));
+
+        let err = repo
+            .list_scripts("", "HEAD", false, CachePolicy::Cached)
+            .await
+            .unwrap_err();
+
+        assert!(
+            matches!(err.downcast_ref(), Some(RemError::ParseError(_))),
+            "{}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn kills_plugins_that_dont_respond() {
+        let (mut repo, _) = test_repo_with_plugin("hang", json!({}), "rem-provider-hang");
+        repo.timeout = Some(1);
+
+        let err = repo
+            .fetch_bytes("deploy.sh", "HEAD", CachePolicy::Cached)
+            .await
+            .unwrap_err();
+
+        assert!(
+            matches!(err.downcast_ref(), Some(RemError::Network(_))),
+            "{}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_plugin_names_with_paths() {
+        for name in ["../evil", "sub/dir", ""] {
+            let err = fetch_project(name, "https://example.com", Map::new())
+                .await
+                .unwrap_err();
+
+            assert!(
+                matches!(err.downcast_ref(), Some(RemError::ParseError(_))),
+                "{}",
+                err
+            );
+        }
+
+        assert!(validate_name("artifactory_v2-beta").is_ok());
+    }
+}
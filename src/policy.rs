@@ -0,0 +1,269 @@
+use crate::{error::RemError, repo::RepoLocation};
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::fs;
+
+/// System-wide policy, which is enforced in addition to the user's own
+pub const SYSTEM_POLICY_PATH: &str = "/etc/rem/policy.toml";
+
+/// Restricts where scripts may be loaded from. Every list that is set must
+/// contain a match for a script to be allowed, unset lists allow everything.
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+#[serde(default)]
+pub struct Policy {
+    /// Hosts scripts may be loaded from, e.g. `github.com`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hosts: Option<Vec<String>>,
+    /// Users, organizations or groups scripts may be loaded from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub orgs: Option<Vec<String>>,
+    /// Saved repos that may be used. Raw git sources are blocked if this is set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repos: Option<Vec<String>>,
+    /// Regular expressions refs must fully match. `HEAD` is used for
+    /// scripts without a ref.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refs: Option<Vec<String>>,
+}
+
+impl Policy {
+    pub fn is_empty(&self) -> bool {
+        self.hosts.is_none() && self.orgs.is_none() && self.repos.is_none() && self.refs.is_none()
+    }
+
+    /// Loads the system-wide policy, if there is one. Failing to read it
+    /// is an error, so a broken policy doesn't allow everything.
+    pub async fn load_system() -> Result<Option<Self>> {
+        let path = Path::new(SYSTEM_POLICY_PATH);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let contents = fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to read `{}`", SYSTEM_POLICY_PATH))?;
+
+        let policy = toml::from_str(&contents).map_err(|err| {
+            RemError::ParseError(format!("Failed to parse `{}`: {}", SYSTEM_POLICY_PATH, err))
+        })?;
+
+        Ok(Some(policy))
+    }
+
+    /// Checks a script source against the policy. `alias` is the saved
+    /// repo's name, or `None` for raw git sources.
+    pub fn check(
+        &self,
+        source: &'static str,
+        alias: Option<&str>,
+        location: &RepoLocation,
+        repo_ref: &str,
+    ) -> Result<()> {
+        self.check_repo(source, alias, location)?;
+        self.check_ref(source, repo_ref)
+    }
+
+    /// Checks everything but the ref
+    pub fn check_repo(
+        &self,
+        source: &'static str,
+        alias: Option<&str>,
+        location: &RepoLocation,
+    ) -> Result<()> {
+        if let Some(hosts) = &self.hosts {
+            if !hosts.iter().any(|h| h.eq_ignore_ascii_case(&location.host)) {
+                let reason = format!("host `{}` is not allowed", location.host);
+                return Err(violation(source, reason).into());
+            }
+        }
+
+        if let Some(orgs) = &self.orgs {
+            if !orgs
+                .iter()
+                .any(|org| org.eq_ignore_ascii_case(location.org()))
+            {
+                let reason = format!("org `{}` is not allowed", location.org());
+                return Err(violation(source, reason).into());
+            }
+        }
+
+        if let Some(repos) = &self.repos {
+            match alias {
+                Some(alias) if repos.iter().any(|repo| repo == alias) => {}
+                Some(alias) => {
+                    let reason = format!("repo `{}` is not allowed", alias);
+                    return Err(violation(source, reason).into());
+                }
+                None => {
+                    let reason = "only saved repos may be used".to_owned();
+                    return Err(violation(source, reason).into());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks the ref against the `refs` patterns
+    pub fn check_ref(&self, source: &'static str, repo_ref: &str) -> Result<()> {
+        if let Some(refs) = &self.refs {
+            let mut allowed = false;
+            for pattern in refs {
+                let regex = Regex::new(&format!("^(?:{})$", pattern))
+                    .with_context(|| format!("Invalid ref pattern `{}` in {}", pattern, source))?;
+
+                allowed |= regex.is_match(repo_ref);
+            }
+
+            if !allowed {
+                let reason = format!("ref `{}` is not allowed", repo_ref);
+                return Err(violation(source, reason).into());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn violation(source: &str, reason: String) -> RemError {
+    RemError::PolicyViolation(format!("Blocked by {}: {}", source, reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn location(host: &str, path: &str) -> RepoLocation {
+        RepoLocation {
+            host: host.to_owned(),
+            path: path.to_owned(),
+        }
+    }
+
+    fn list(items: &[&str]) -> Option<Vec<String>> {
+        Some(items.iter().map(|item| item.to_string()).collect())
+    }
+
+    fn is_violation(result: Result<()>) -> bool {
+        matches!(
+            result.map_err(|err| err.downcast::<RemError>()),
+            Err(Ok(RemError::PolicyViolation(_)))
+        )
+    }
+
+    #[test]
+    fn empty_policy_allows_everything() {
+        let policy = Policy::default();
+        let location = location("git.example.com", "anyone/scripts");
+        assert!(policy.check("policy", None, &location, "HEAD").is_ok());
+    }
+
+    #[test]
+    fn checks_hosts() {
+        let policy = Policy {
+            hosts: list(&["github.com"]),
+            ..Default::default()
+        };
+
+        let allowed = location("GitHub.com", "company/scripts");
+        let blocked = location("gitlab.com", "company/scripts");
+        assert!(policy.check("policy", None, &allowed, "HEAD").is_ok());
+        assert!(is_violation(policy.check("policy", None, &blocked, "HEAD")));
+    }
+
+    #[test]
+    fn checks_orgs() {
+        let policy = Policy {
+            orgs: list(&["company"]),
+            ..Default::default()
+        };
+
+        let allowed = location("github.com", "Company/scripts");
+        let blocked = location("github.com", "company-fork/scripts");
+        assert!(policy.check("policy", None, &allowed, "HEAD").is_ok());
+        assert!(is_violation(policy.check("policy", None, &blocked, "HEAD")));
+    }
+
+    #[test]
+    fn checks_repos_and_blocks_raw_git_sources() {
+        let policy = Policy {
+            repos: list(&["ci"]),
+            ..Default::default()
+        };
+
+        let location = location("github.com", "company/ci");
+        assert!(policy
+            .check("policy", Some("ci"), &location, "HEAD")
+            .is_ok());
+        assert!(is_violation(policy.check(
+            "policy",
+            Some("tools"),
+            &location,
+            "HEAD"
+        )));
+        assert!(is_violation(
+            policy.check("policy", None, &location, "HEAD")
+        ));
+    }
+
+    #[test]
+    fn refs_must_match_fully() {
+        let policy = Policy {
+            refs: list(&[r"v[0-9]+\.[0-9]+\.[0-9]+"]),
+            ..Default::default()
+        };
+
+        let location = location("github.com", "company/ci");
+        for allowed in ["v1.2.3", "v10.0.0"] {
+            assert!(policy.check("policy", None, &location, allowed).is_ok());
+        }
+
+        for blocked in ["HEAD", "main", "v1.2", "v1.2.3-rc1", "xv1.2.3"] {
+            assert!(
+                is_violation(policy.check("policy", None, &location, blocked)),
+                "{}",
+                blocked
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_ref_patterns() {
+        let policy = Policy {
+            refs: list(&["v[0-9"]),
+            ..Default::default()
+        };
+
+        let result = policy.check("policy", None, &location("github.com", "a/b"), "v1");
+        assert!(result.is_err());
+        assert!(!is_violation(result));
+    }
+
+    #[test]
+    fn system_policy_is_enforced_over_user_policy() {
+        let config = Config {
+            policy: Policy {
+                hosts: list(&["github.com", "git.example.com"]),
+                ..Default::default()
+            },
+            system_policy: Some(Policy {
+                hosts: list(&["github.com"]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let allowed = location("github.com", "company/ci");
+        let blocked = location("git.example.com", "company/ci");
+        assert!(config.check_policy(None, &allowed, "HEAD").is_ok());
+
+        let err = config
+            .check_policy(None, &blocked, "HEAD")
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with("Blocked by system policy"), "{}", err);
+    }
+}
@@ -0,0 +1,217 @@
+use crate::error::RemError;
+use anyhow::Result;
+use async_process::{Child, ExitStatus};
+use std::{os::unix::process::ExitStatusExt, time::Duration};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    time::{sleep, sleep_until, Instant},
+};
+
+/// Time between SIGTERM and SIGKILL, unless another one is given
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// How often to check whether a process group is gone after signalling it
+const GROUP_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Whether any process in the given process group is still running
+fn is_group_alive(pgid: libc::pid_t) -> bool {
+    unsafe { libc::kill(-pgid, 0) == 0 }
+}
+
+/// Sends a signal to every process in the given process group
+fn signal_group(pgid: libc::pid_t, sig: libc::c_int) {
+    unsafe {
+        libc::kill(-pgid, sig);
+    }
+}
+
+/// Moves the script's process group to the foreground of the controlling
+/// terminal, so it can still read from it, and gives the terminal back
+/// to rem once dropped.
+struct ForegroundGuard {
+    restore: Option<libc::pid_t>,
+}
+
+impl ForegroundGuard {
+    fn take(pgid: libc::pid_t) -> Self {
+        unsafe {
+            let own_pgid = libc::getpgrp();
+            let is_foreground = libc::isatty(libc::STDIN_FILENO) == 1
+                && libc::tcgetpgrp(libc::STDIN_FILENO) == own_pgid;
+
+            if is_foreground && libc::tcsetpgrp(libc::STDIN_FILENO, pgid) == 0 {
+                return Self {
+                    restore: Some(own_pgid),
+                };
+            }
+        }
+
+        Self { restore: None }
+    }
+}
+
+impl Drop for ForegroundGuard {
+    fn drop(&mut self) {
+        if let Some(pgid) = self.restore {
+            // We're a background process at this point, and would be stopped
+            // by SIGTTOU when taking the terminal back
+            unsafe {
+                let prev = libc::signal(libc::SIGTTOU, libc::SIG_IGN);
+                libc::tcsetpgrp(libc::STDIN_FILENO, pgid);
+                libc::signal(libc::SIGTTOU, prev);
+            }
+        }
+    }
+}
+
+/// Puts the calling process into its own process group. Used before
+/// exec'ing a script, so it and all of its children can be signalled
+/// together.
+pub fn new_process_group() -> std::io::Result<()> {
+    unsafe {
+        libc::setpgid(0, 0);
+    }
+
+    Ok(())
+}
+
+/// Waits for a child that was started in its own process group, while
+/// forwarding SIGINT, SIGTERM and SIGHUP to it. If a timeout is given, the
+/// group receives SIGTERM once it expires and SIGKILL after the grace period.
+pub async fn wait_for_group(
+    child: &mut Child,
+    timeout: Option<Duration>,
+    grace_period: Duration,
+) -> Result<ExitStatus> {
+    let pgid = child.id() as libc::pid_t;
+
+    // The child does this itself before exec, but doing it here as well
+    // makes sure the group exists before we send anything to it
+    unsafe {
+        libc::setpgid(pgid, pgid);
+    }
+
+    let _foreground = ForegroundGuard::take(pgid);
+
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sighup = signal(SignalKind::hangup())?;
+
+    let mut term_at = timeout.map(|timeout| Instant::now() + timeout);
+    let mut kill_at = None;
+    let mut timed_out = false;
+    let mut interrupted = false;
+
+    let status = child.status();
+    tokio::pin!(status);
+
+    let status = loop {
+        tokio::select! {
+            status = &mut status => break status?,
+            _ = sigint.recv() => {
+                signal_group(pgid, libc::SIGINT);
+                interrupted = true;
+            }
+            _ = sigterm.recv() => {
+                signal_group(pgid, libc::SIGTERM);
+                interrupted = true;
+            }
+            _ = sighup.recv() => {
+                signal_group(pgid, libc::SIGHUP);
+                interrupted = true;
+            }
+            _ = sleep_until(term_at.unwrap_or_else(Instant::now)), if term_at.is_some() => {
+                signal_group(pgid, libc::SIGTERM);
+                timed_out = true;
+                term_at = None;
+                kill_at = Some(Instant::now() + grace_period);
+            }
+            _ = sleep_until(kill_at.unwrap_or_else(Instant::now)), if kill_at.is_some() => {
+                signal_group(pgid, libc::SIGKILL);
+                kill_at = None;
+            }
+        }
+    };
+
+    // Background jobs ignore SIGINT, so they can outlive a script that was
+    // interrupted. Once the script is gone, we make sure they're stopped too,
+    // with SIGKILL for the ones still running after the grace period.
+    if interrupted || timed_out || status.signal().is_some() {
+        signal_group(pgid, libc::SIGTERM);
+
+        let kill_at = kill_at.unwrap_or_else(|| Instant::now() + grace_period);
+        while is_group_alive(pgid) && Instant::now() < kill_at {
+            sleep(GROUP_POLL_INTERVAL).await;
+        }
+
+        if is_group_alive(pgid) {
+            signal_group(pgid, libc::SIGKILL);
+        }
+    }
+
+    if timed_out {
+        let timeout = timeout.unwrap_or_default();
+        let message = format!(
+            "Script timed out after {}",
+            humantime::format_duration(timeout)
+        );
+
+        return Err(RemError::TimedOut(message).into());
+    }
+
+    Ok(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_process::{unix::CommandExt, Command};
+    use std::{env, fs};
+
+    /// Whether the process exists and isn't a zombie waiting to be reaped
+    fn is_running(pid: &str) -> bool {
+        match fs::read_to_string(format!("/proc/{}/stat", pid)) {
+            Ok(stat) => !stat
+                .rsplit_once(')')
+                .is_some_and(|(_, rest)| rest.trim_start().starts_with('Z')),
+            Err(_) => false,
+        }
+    }
+
+    #[tokio::test]
+    async fn kills_processes_ignoring_sigterm_after_timeout() {
+        let pid_file = env::temp_dir().join(format!("rem-process-test-{}", std::process::id()));
+        let script = format!(
+            "(trap '' TERM; exec sleep 25) & echo $! > {}; sleep 1000",
+            pid_file.display()
+        );
+
+        let mut cmd = Command::new("bash");
+        cmd.args(["-c", &script]);
+        unsafe {
+            cmd.pre_exec(new_process_group);
+        }
+
+        let mut child = cmd.spawn().unwrap();
+        let timeout = Some(Duration::from_millis(500));
+        let err = wait_for_group(&mut child, timeout, Duration::from_millis(300))
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err.downcast_ref(), Some(RemError::TimedOut(_))));
+
+        let pid = fs::read_to_string(&pid_file).unwrap();
+        fs::remove_file(&pid_file).unwrap();
+        // SIGKILL was sent, but the process may take a moment to exit
+        let pid = pid.trim();
+        for _ in 0..20 {
+            if !is_running(pid) {
+                return;
+            }
+
+            sleep(Duration::from_millis(50)).await;
+        }
+
+        panic!("sleep {} is still running", pid);
+    }
+}
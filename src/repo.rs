@@ -1,18 +1,78 @@
-use anyhow::Result;
+use crate::{
+    config::get_cache_dir, error::RemError, http::HttpClient, process, sandbox::SandboxProfile,
+    FetchedScript,
+};
+use anyhow::{bail, Context, Result};
+use async_process::{unix::CommandExt, Command, ExitStatus};
 use async_trait::async_trait;
-use async_process::{Command, ExitStatus};
-use std::fmt::Debug;
-use tokio::io::{self, AsyncWriteExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Reverse,
+    collections::BTreeMap as Map,
+    env,
+    ffi::OsStr,
+    fmt::Debug,
+    fs::Permissions,
+    os::unix::{ffi::OsStrExt, fs::PermissionsExt},
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    fs,
+    io::{self, AsyncWriteExt},
+};
 
-const SHELL_NAME: &'static str = "rem";
+const SHELL_NAME: &str = "rem";
+
+/// Variables that are kept when the environment is cleared, since most
+/// scripts won't work without them
+pub const DEFAULT_KEEP_ENV: &[&str] = &["PATH", "HOME", "USER", "TERM", "LANG"];
 
 #[async_trait]
 #[typetag::serde(tag = "provider")]
-pub trait Repo {
+pub trait Repo: Send + Sync {
     fn provider(&self) -> &'static str;
     fn readable(&self) -> String;
+    fn location(&self) -> RepoLocation;
+    fn options(&self) -> &RepoOptions;
     fn box_clone(&self) -> Box<dyn Repo>;
-    async fn fetch_script(&self, path: &str, repo_ref: &str, fresh: bool) -> Result<String>;
+    /// Hands the client configured in the `[http]` section to providers
+    /// that use it
+    fn set_http_client(&mut self, _client: HttpClient) {}
+    /// Fetches a file exactly as it's stored in the repository
+    async fn fetch_bytes(&self, path: &str, repo_ref: &str, cache: CachePolicy) -> Result<Vec<u8>>;
+    /// Fetches a file as text, for reading its metadata or showing it.
+    /// Invalid UTF-8 is replaced, so this must not be used to run scripts.
+    async fn fetch_script(&self, path: &str, repo_ref: &str, cache: CachePolicy) -> Result<String> {
+        let contents = self.fetch_bytes(path, repo_ref, cache).await?;
+        Ok(String::from_utf8_lossy(&contents).into_owned())
+    }
+    async fn list_scripts(
+        &self,
+        dir: &str,
+        repo_ref: &str,
+        recursive: bool,
+        cache: CachePolicy,
+    ) -> Result<Vec<RepoEntry>>;
+    async fn resolve_commit(&self, repo_ref: &str, cache: CachePolicy) -> Result<String>;
+    /// Lists the names of the repo's tags and branches, ordered by
+    /// `order_refs`
+    async fn list_refs(&self, cache: CachePolicy) -> Result<Vec<String>>;
+    /// Fetches a file together with the commit it was fetched at. The
+    /// commit is resolved first and the file fetched at it, so a branch
+    /// moving in between can't make the two disagree.
+    async fn fetch_at_commit(
+        &self,
+        path: &str,
+        repo_ref: &str,
+        cache: CachePolicy,
+    ) -> Result<(Vec<u8>, String)> {
+        let commit = self.resolve_commit(repo_ref, cache).await?;
+        let contents = self.fetch_bytes(path, &commit, cache).await?;
+        Ok((contents, commit))
+    }
+    /// Returns the local checkout of the ref, for providers that keep one
+    async fn checkout(&self, repo_ref: &str, cache: CachePolicy) -> Result<Option<PathBuf>>;
 }
 
 impl Debug for Box<dyn Repo> {
@@ -21,17 +81,508 @@ impl Debug for Box<dyn Repo> {
     }
 }
 
-pub async fn run_script(script: &str, script_args: Vec<&str>) -> Result<ExitStatus> {
-    let mut cmd = Command::new("bash");
-    let mut args = vec!["-c", script, SHELL_NAME];
-    args.extend_from_slice(&script_args);
+/// Settings shared by all providers, flattened into each saved repo entry
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct RepoOptions {
+    /// Seconds after which a cached mutable ref (branch, `HEAD`) is checked
+    /// against the remote. Overrides the global `ref_ttl`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ref_ttl: Option<u64>,
+
+    /// Environment variables set for every script run from this repo
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub env: Map<String, String>,
+
+    /// Sandbox profile that scripts from this repo are run in by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sandbox: Option<String>,
+}
+
+/// Where a repository is hosted, e.g. `github.com` and `user/scripts`
+#[derive(Clone, Debug)]
+pub struct RepoLocation {
+    pub host: String,
+    pub path: String,
+}
+
+impl RepoLocation {
+    /// The user, organization or group owning the repository
+    pub fn org(&self) -> &str {
+        self.path.split('/').next().unwrap_or_default()
+    }
+}
+
+/// A file or directory in a repository, with its path relative to the repo root
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RepoEntry {
+    pub path: String,
+    pub is_dir: bool,
+}
+
+/// Puts the tags first, with the newest version first, followed by the
+/// branches. Numbers in tags are compared by value, so `v1.10.0` comes
+/// before `v1.9.0`.
+pub fn order_refs(mut tags: Vec<String>, branches: Vec<String>) -> Vec<String> {
+    tags.sort_by_cached_key(|tag| Reverse(version_key(tag)));
+    tags.extend(branches);
+    tags
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum VersionPart {
+    Text(String),
+    Number(u64),
+}
+
+/// Splits a tag into runs of digits and other characters
+fn version_key(tag: &str) -> Vec<VersionPart> {
+    let mut parts = Vec::new();
+    let mut rest = tag;
+    while let Some(first) = rest.chars().next() {
+        let is_digit = first.is_ascii_digit();
+        let end = rest
+            .find(|c: char| c.is_ascii_digit() != is_digit)
+            .unwrap_or(rest.len());
+
+        let (run, tail) = rest.split_at(end);
+        parts.push(match is_digit {
+            true => VersionPart::Number(run.parse().unwrap_or(u64::MAX)),
+            false => VersionPart::Text(run.to_owned()),
+        });
+        rest = tail;
+    }
+
+    parts
+}
+
+/// Determines how cached checkouts of a ref are reused
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CachePolicy {
+    /// Discard any cached copy and fetch again
+    Fresh,
+    /// Check mutable refs against the remote once they're older than the
+    /// given number of seconds, and re-fetch if the commit changed
+    Ttl(u64),
+    /// Reuse cached copies indefinitely
+    Cached,
+}
+
+/// Controls the environment a script is executed in
+#[derive(Debug)]
+pub struct RunOptions {
+    /// Variables to set, on top of the inherited environment
+    pub env: Map<String, String>,
+    /// Start from an empty environment, only keeping `DEFAULT_KEEP_ENV`
+    /// and the variables in `keep_env`
+    pub clear_env: bool,
+    pub keep_env: Vec<String>,
+    /// Working directory for the script, defaults to the current one
+    pub cwd: Option<PathBuf>,
+    /// Maximum run time, after which the script is sent SIGTERM
+    pub timeout: Option<Duration>,
+    /// Time between SIGTERM and SIGKILL when the timeout expires
+    pub grace_period: Duration,
+    /// Run the script inside a sandbox with the given restrictions
+    pub sandbox: Option<SandboxProfile>,
+}
 
-    cmd.args(&args);
-    let mut child = cmd.spawn()?;
-    Ok(child.status().await?)
+impl Default for RunOptions {
+    fn default() -> Self {
+        Self {
+            env: Map::new(),
+            clear_env: false,
+            keep_env: Vec::new(),
+            cwd: None,
+            timeout: None,
+            grace_period: process::DEFAULT_GRACE_PERIOD,
+            sandbox: None,
+        }
+    }
 }
 
-pub async fn import_script(script: &str) -> Result<()> {
-    io::stdout().write_all(script.as_bytes()).await?;
+/// What bash should execute
+pub enum ScriptInput<'a> {
+    /// Script contents, passed with `bash -c`. Contents with NUL bytes
+    /// can't be passed as an argument, so they're run from a temporary
+    /// file instead.
+    Inline(&'a [u8]),
+    /// A script file on disk, so `$0` and `BASH_SOURCE` point to it
+    File(&'a Path),
+}
+
+/// A file in a repository and its contents
+pub struct RepoFile {
+    pub path: String,
+    pub contents: Vec<u8>,
+}
+
+/// A script written to a temporary directory together with the other files
+/// in its directory
+pub struct Materialized {
+    pub script_path: PathBuf,
+    temp_dir: PathBuf,
+}
+
+impl Materialized {
+    /// Removes the files that were written for the run
+    pub async fn cleanup(self) -> Result<()> {
+        fs::remove_dir_all(self.temp_dir).await?;
+        Ok(())
+    }
+}
+
+/// Writes the script and its sibling files to a temporary directory, so
+/// it can use them. Only the given contents are written, which are the ones
+/// that were fetched and reviewed.
+pub async fn materialize_script(
+    path: &str,
+    contents: &[u8],
+    siblings: &[RepoFile],
+) -> Result<Materialized> {
+    let temp_dir = get_run_path("").await?;
+
+    let materialized = Materialized {
+        script_path: join_repo_path(&temp_dir, path)?,
+        temp_dir: temp_dir.clone(),
+    };
+
+    let result = async {
+        for file in siblings {
+            write_file(&join_repo_path(&temp_dir, &file.path)?, &file.contents).await?;
+        }
+
+        write_file(&materialized.script_path, contents).await
+    };
+
+    if let Err(err) = result.await {
+        let _ = materialized.cleanup().await;
+        return Err(err);
+    }
+
+    Ok(materialized)
+}
+
+/// Returns a unique path in the cache dir for files that are only needed
+/// during a single run
+async fn get_run_path(suffix: &str) -> Result<PathBuf> {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+
+    let mut path = get_cache_dir().await?;
+    path.push("run");
+    fs::create_dir_all(&path).await?;
+    path.push(format!("{}-{}{}", std::process::id(), nanos, suffix));
+    Ok(path)
+}
+
+/// Fetches the files next to the script, without subdirectories or the
+/// script itself, at the commit the script was fetched at. Providers with a
+/// local checkout read them from there, after making sure it still contains
+/// the fetched script.
+pub async fn fetch_siblings(
+    repo: &dyn Repo,
+    path: &str,
+    script: &FetchedScript,
+    repo_ref: &str,
+    cache: CachePolicy,
+) -> Result<Vec<RepoFile>> {
+    let checkout = repo.checkout(repo_ref, cache).await?;
+    if let Some(checkout) = &checkout {
+        let script_path = join_repo_path(checkout, path)?;
+        if fs::read(&script_path).await.ok().as_deref() != Some(script.contents.as_slice()) {
+            bail!(
+                "Script `{}` changed in the checkout after it was fetched, try running it again",
+                path
+            );
+        }
+    }
+
+    let script_dir = Path::new(path)
+        .parent()
+        .and_then(Path::to_str)
+        .unwrap_or_default();
+
+    // Checkouts are listed by their ref, since that's what they're cached by
+    let list_ref = match checkout {
+        Some(_) => repo_ref,
+        None => &script.commit,
+    };
+
+    let entries = repo
+        .list_scripts(script_dir, list_ref, false, cache)
+        .await?;
+    let mut files = Vec::new();
+    for entry in entries
+        .into_iter()
+        .filter(|entry| !entry.is_dir && entry.path != path)
+    {
+        let contents = match &checkout {
+            Some(checkout) => fs::read(join_repo_path(checkout, &entry.path)?).await?,
+            None => repo.fetch_bytes(&entry.path, &script.commit, cache).await?,
+        };
+
+        files.push(RepoFile {
+            path: entry.path,
+            contents,
+        });
+    }
+
+    Ok(files)
+}
+
+/// Joins a path in the repo onto `target`. Paths come from APIs and
+/// plugins, so anything that could point outside of `target`, like absolute
+/// paths or `..`, is rejected.
+fn join_repo_path(target: &Path, path: &str) -> Result<PathBuf> {
+    let relative = Path::new(path);
+    let mut components = relative.components().peekable();
+    let is_inside = components.peek().is_some()
+        && components.all(|component| matches!(component, Component::Normal(_)));
+
+    if !is_inside {
+        let message = format!(
+            "Refusing to write `{}` outside of the script's directory",
+            path
+        );
+        return Err(RemError::ParseError(message).into());
+    }
+
+    Ok(target.join(relative))
+}
+
+async fn write_file(file_path: &Path, contents: &[u8]) -> Result<()> {
+    if let Some(parent) = file_path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+
+    fs::write(file_path, contents).await?;
+
+    // The APIs don't tell us about file modes, so we assume that
+    // everything with a shebang is meant to be executed
+    if contents.starts_with(b"#!") {
+        fs::set_permissions(file_path, Permissions::from_mode(0o755)).await?;
+    }
+
+    Ok(())
+}
+
+pub async fn run_script(
+    script: ScriptInput<'_>,
+    script_args: Vec<&str>,
+    opts: &RunOptions,
+) -> Result<ExitStatus> {
+    let mut cmd = match &opts.sandbox {
+        Some(profile) => profile.command("bash")?,
+        None => Command::new("bash"),
+    };
+
+    let temp_script = match script {
+        ScriptInput::Inline(contents) if contents.contains(&0) => {
+            let path = get_run_path(".sh").await?;
+            fs::write(&path, contents).await?;
+            Some(path)
+        }
+        _ => None,
+    };
+
+    match (script, &temp_script) {
+        (_, Some(path)) => cmd.arg(path),
+        (ScriptInput::File(path), None) => cmd.arg(path),
+        (ScriptInput::Inline(contents), None) => cmd
+            .arg("-c")
+            .arg(OsStr::from_bytes(contents))
+            .arg(SHELL_NAME),
+    };
+
+    if opts.clear_env {
+        cmd.env_clear();
+
+        let keep = DEFAULT_KEEP_ENV
+            .iter()
+            .copied()
+            .chain(opts.keep_env.iter().map(String::as_str));
+
+        for name in keep {
+            if let Some(value) = env::var_os(name) {
+                cmd.env(name, value);
+            }
+        }
+    }
+
+    if let Some(cwd) = &opts.cwd {
+        cmd.current_dir(cwd);
+    }
+
+    cmd.envs(&opts.env);
+    cmd.args(script_args.iter().map(OsStr::new));
+
+    // Running the script in its own process group lets us forward signals
+    // to everything it started, and clean up after it on timeouts
+    unsafe {
+        cmd.pre_exec(process::new_process_group);
+    }
+
+    let status = async {
+        let mut child = cmd.spawn()?;
+        process::wait_for_group(&mut child, opts.timeout, opts.grace_period).await
+    }
+    .await;
+
+    if let Some(path) = temp_script {
+        let _ = fs::remove_file(path).await;
+    }
+
+    status
+}
+
+/// Parses a `KEY=VALUE` pair, as passed to `--env`
+pub fn parse_env_pair(pair: &str) -> Result<(String, String)> {
+    match pair.split_once('=') {
+        Some(("", _)) => {
+            Err(RemError::ParseError(format!("Empty variable name in `{}`", pair)).into())
+        }
+        Some((key, value)) => Ok((key.to_owned(), value.to_owned())),
+        None => Err(RemError::ParseError(format!("Expected `KEY=VALUE`, got `{}`", pair)).into()),
+    }
+}
+
+/// Reads variables from a dotenv style file. Empty lines and comments are
+/// skipped, and values may be wrapped in single or double quotes.
+pub async fn parse_env_file(path: &Path) -> Result<Map<String, String>> {
+    let contents = fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Failed to read env file `{}`", path.display()))?;
+
+    let mut vars = Map::new();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = parse_env_pair(line)?;
+        let value = value.trim();
+        let value = match value.as_bytes() {
+            [b'"', .., b'"'] | [b'\'', .., b'\''] => &value[1..value.len() - 1],
+            _ => value,
+        };
+
+        vars.insert(key.trim().to_owned(), value.to_owned());
+    }
+
+    Ok(vars)
+}
+
+pub async fn import_script(script: &[u8]) -> Result<()> {
+    let mut stdout = io::stdout();
+    stdout.write_all(script).await?;
+    stdout.flush().await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn joins_repo_paths_inside_target() {
+        let target = Path::new("/tmp/run");
+        assert_eq!(
+            join_repo_path(target, "util/lib.sh").unwrap(),
+            Path::new("/tmp/run/util/lib.sh")
+        );
+
+        for path in ["/etc/passwd", "../lib.sh", "util/../../lib.sh", "", "."] {
+            assert!(join_repo_path(target, path).is_err(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn orders_newest_tags_first_then_branches() {
+        let names = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+        let refs = order_refs(
+            names(&["v1.9.0", "v1.10.0", "v0.2", "v1.10.1"]),
+            names(&["main", "develop"]),
+        );
+
+        assert_eq!(
+            refs,
+            ["v1.10.1", "v1.10.0", "v1.9.0", "v0.2", "main", "develop"]
+        );
+    }
+
+    #[test]
+    fn parses_env_pairs() {
+        assert_eq!(
+            parse_env_pair("STAGE=prod").unwrap(),
+            ("STAGE".to_owned(), "prod".to_owned())
+        );
+        assert_eq!(
+            parse_env_pair("URL=https://example.com/?a=b").unwrap(),
+            ("URL".to_owned(), "https://example.com/?a=b".to_owned())
+        );
+        assert_eq!(
+            parse_env_pair("EMPTY=").unwrap(),
+            ("EMPTY".to_owned(), String::new())
+        );
+
+        for pair in ["STAGE", "=prod", ""] {
+            let err = parse_env_pair(pair).unwrap_err();
+            assert!(
+                matches!(err.downcast_ref(), Some(RemError::ParseError(_))),
+                "{}",
+                pair
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn parses_env_files() {
+        let path = env::temp_dir().join(format!("rem-env-test-{}", std::process::id()));
+        let contents = "# Deployment settings\n\
+            \n\
+            STAGE=prod\n\
+            \x20 export REGION = eu-west-1 \n\
+            GREETING=\"hello world\"\n\
+            SINGLE='it''s'\n\
+            MIXED=\"unbalanced'\n\
+            QUERY=a=b&c=d\n\
+            HASH=#not-a-comment\n";
+        fs::write(&path, contents).await.unwrap();
+
+        let vars = parse_env_file(&path).await;
+        fs::remove_file(&path).await.unwrap();
+
+        let expected = [
+            ("GREETING", "hello world"),
+            ("HASH", "#not-a-comment"),
+            ("MIXED", "\"unbalanced'"),
+            ("QUERY", "a=b&c=d"),
+            ("REGION", "eu-west-1"),
+            ("SINGLE", "it''s"),
+            ("STAGE", "prod"),
+        ];
+
+        let vars = vars.unwrap();
+        let vars = vars
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(vars, expected);
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_env_file_lines() {
+        let path = env::temp_dir().join(format!("rem-env-test-bad-{}", std::process::id()));
+        fs::write(&path, "STAGE=prod\nNOT A PAIR\n").await.unwrap();
+
+        let result = parse_env_file(&path).await;
+        fs::remove_file(&path).await.unwrap();
+
+        let err = result.unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(RemError::ParseError(_))));
+        assert!(err.to_string().contains("NOT A PAIR"), "{}", err);
+    }
+}
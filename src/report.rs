@@ -0,0 +1,57 @@
+//! Warnings and progress messages shown while `rem` works. They're printed
+//! to stderr as text, or as single lines of JSON once JSON output is
+//! enabled, so stderr stays machine readable.
+
+use serde::Serialize;
+use std::{
+    fmt::Display,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+#[derive(Serialize)]
+struct WarningOutput {
+    warning: WarningDetails,
+}
+
+#[derive(Serialize)]
+struct WarningDetails {
+    message: String,
+}
+
+/// Switches all further messages to JSON
+pub fn set_json(json: bool) {
+    JSON.store(json, Ordering::Relaxed);
+}
+
+pub fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// Prints something that went wrong without stopping `rem`, e.g.
+/// `{"warning": {"message": "..."}}` in JSON
+pub fn warn(message: impl Display) {
+    if !is_json() {
+        eprintln!("Warning: {}", message);
+        return;
+    }
+
+    let output = WarningOutput {
+        warning: WarningDetails {
+            message: message.to_string(),
+        },
+    };
+
+    if let Ok(json) = serde_json::to_string(&output) {
+        eprintln!("{}", json);
+    }
+}
+
+/// Prints what `rem` is currently doing. Progress is only meant for
+/// people, so it's left out of JSON output.
+pub fn progress(message: impl Display) {
+    if !is_json() {
+        eprintln!("{}", message);
+    }
+}
@@ -0,0 +1,138 @@
+use crate::meta::is_on_path;
+use anyhow::{bail, Context, Result};
+use async_process::Command;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// Name of the profile that's used when no other one is given. It doesn't
+/// need to be defined in the config.
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Prepares the mounts for the `unshare` fallback, since it can't set them
+/// up by itself. Expects the bind paths as arguments, followed by `--` and
+/// the command to run.
+const UNSHARE_SETUP: &str = r#"
+set -e
+
+# Binds from the old /tmp are resolved relative to the working directory,
+# which keeps pointing to it once the new one is mounted
+cwd="$PWD"
+cd /tmp
+mount -t tmpfs tmpfs /tmp
+
+binds=0
+for bind; do
+    [ "$bind" = -- ] && break
+    case "$bind" in
+        /tmp|/tmp/*)
+            source=".${bind#/tmp}"
+            if [ -d "$source" ]; then
+                mkdir -p "$bind"
+            else
+                mkdir -p "$(dirname "$bind")"
+                touch "$bind"
+            fi
+            ;;
+        *) source="$bind" ;;
+    esac
+
+    mount --no-canonicalize --rbind "$source" "$bind"
+    binds=$((binds + 1))
+done
+cd "$cwd" 2>/dev/null || cd /
+
+while read -r _ _ _ _ mount _; do
+    case "$mount" in
+        /proc|/proc/*|/dev|/dev/*|/sys|/sys/*|/tmp|*\\*) continue ;;
+    esac
+
+    i=0
+    for bind; do
+        [ "$i" -lt "$binds" ] || break
+        case "$mount" in "$bind"|"$bind"/*) continue 2 ;; esac
+        i=$((i + 1))
+    done
+
+    if [ "$mount" = / ]; then
+        mount -o remount,bind,ro /
+    else
+        mount -o remount,bind,ro "$mount" 2>/dev/null || true
+    fi
+done < /proc/self/mountinfo
+
+shift "$((binds + 1))"
+exec "$@"
+"#;
+
+/// Restrictions for scripts run with `--sandbox`, defined in the config
+/// as `[sandbox.<name>]`. The root filesystem is always mounted read-only
+/// and `/tmp` is replaced with an empty one.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct SandboxProfile {
+    /// Allow the script to access the network
+    pub network: bool,
+    /// Paths that stay writable inside the sandbox
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub bind: Vec<PathBuf>,
+}
+
+impl Default for SandboxProfile {
+    fn default() -> Self {
+        Self {
+            network: true,
+            bind: Vec::new(),
+        }
+    }
+}
+
+impl SandboxProfile {
+    /// Creates a command that runs `program` inside the sandbox, using
+    /// bubblewrap if it's installed and `unshare` otherwise
+    pub fn command(&self, program: &str) -> Result<Command> {
+        let binds = self
+            .bind
+            .iter()
+            .map(|path| {
+                path.canonicalize()
+                    .with_context(|| format!("Bind path `{}` was not found", path.display()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        if is_on_path("bwrap") {
+            let mut cmd = Command::new("bwrap");
+            cmd.args(["--ro-bind", "/", "/"])
+                .args(["--dev", "/dev", "--proc", "/proc", "--tmpfs", "/tmp"])
+                .args(["--unshare-pid", "--unshare-ipc", "--unshare-uts"])
+                .arg("--die-with-parent");
+
+            if !self.network {
+                cmd.arg("--unshare-net");
+            }
+
+            for bind in &binds {
+                cmd.arg("--bind").arg(bind).arg(bind);
+            }
+
+            cmd.args(["--", program]);
+            return Ok(cmd);
+        }
+
+        if is_on_path("unshare") {
+            let mut cmd = Command::new("unshare");
+            cmd.args(["--user", "--map-root-user", "--mount", "--ipc", "--uts"])
+                .args(["--pid", "--fork", "--mount-proc", "--kill-child"]);
+
+            if !self.network {
+                cmd.arg("--net");
+            }
+
+            cmd.args(["--", "sh", "-c", UNSHARE_SETUP, "rem-sandbox"])
+                .args(&binds)
+                .args(["--", program]);
+            return Ok(cmd);
+        }
+
+        bail!("Running scripts in a sandbox requires `bwrap` or `unshare` to be installed")
+    }
+}
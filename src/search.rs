@@ -0,0 +1,205 @@
+use crate::{
+    config::{get_cache_dir, Config},
+    error::RemError,
+    meta::ScriptMeta,
+    repo::Repo,
+    report,
+};
+use anyhow::{Context, Result};
+use fuzzy_matcher::{skim::SkimMatcherV2, FuzzyMatcher};
+use serde::{Deserialize, Serialize};
+use std::{cmp::Reverse, collections::BTreeMap as Map, path::PathBuf};
+use tokio::fs;
+
+const INDEX_FILE: &str = "search-index.json";
+
+/// Local index of the scripts in all saved repositories
+#[derive(Default, Serialize, Deserialize)]
+pub struct SearchIndex {
+    repos: Map<String, IndexedRepo>,
+}
+
+/// The scripts of a repo, at the ref the policies allowed when it was indexed
+#[derive(Serialize, Deserialize)]
+struct IndexedRepo {
+    #[serde(rename = "ref")]
+    rref: String,
+    entries: Vec<IndexEntry>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub path: String,
+    pub description: Option<String>,
+}
+
+pub struct SearchResult<'a> {
+    pub repo: &'a str,
+    /// The ref the script was indexed at
+    pub rref: &'a str,
+    pub entry: &'a IndexEntry,
+    score: i64,
+}
+
+impl SearchResult<'_> {
+    /// The script as a source for `rem run`, with the ref unless it's `HEAD`
+    pub fn script_name(&self) -> String {
+        match self.rref {
+            "HEAD" => format!("{}:{}", self.repo, self.entry.path),
+            rref => format!("{}@{}:{}", self.repo, rref, self.entry.path),
+        }
+    }
+}
+
+async fn get_index_path() -> Result<PathBuf> {
+    let mut path = get_cache_dir().await?;
+    path.push(INDEX_FILE);
+    Ok(path)
+}
+
+impl SearchIndex {
+    /// Loads the index from the cache dir. A missing or unreadable index
+    /// is treated as empty, since it can always be rebuilt.
+    pub async fn load() -> Result<Self> {
+        let path = get_index_path().await?;
+        let index = match fs::read_to_string(&path).await {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_default(),
+            Err(_) => Self::default(),
+        };
+
+        Ok(index)
+    }
+
+    pub async fn save(&self) -> Result<()> {
+        let path = get_index_path().await?;
+        let contents = serde_json::to_string(self).context("Failed to serialize index")?;
+        fs::write(path, contents)
+            .await
+            .context("Failed to write search index")?;
+
+        Ok(())
+    }
+
+    /// Indexes all saved repos that aren't in the index yet, at `HEAD` or
+    /// the newest ref the policies allow, see `Config::allowed_ref`. Repos
+    /// that were removed from the config or whose ref is blocked by now
+    /// are dropped. If `refresh` is set, all repos are indexed again.
+    pub async fn update(&mut self, config: &Config, refresh: bool) -> Result<()> {
+        if refresh {
+            self.repos.clear();
+        }
+
+        self.repos.retain(|name, indexed| {
+            config.repo.get(name).is_some_and(|repo| {
+                config
+                    .check_policy(Some(name), &repo.location(), &indexed.rref)
+                    .is_ok()
+            })
+        });
+
+        for (name, repo) in &config.repo {
+            if self.repos.contains_key(name) {
+                continue;
+            }
+
+            let indexed = async {
+                let rref = match config.allowed_ref(name, repo.as_ref()).await? {
+                    Some(rref) => rref,
+                    None => return Ok(None),
+                };
+
+                report::progress(format!("Indexing `{}`...", name));
+                let entries = index_repo(name, repo.as_ref(), &rref, config).await?;
+                Ok(Some(IndexedRepo { rref, entries }))
+            };
+
+            match indexed.await {
+                Ok(Some(indexed)) => {
+                    self.repos.insert(name.clone(), indexed);
+                }
+                Ok(None) => {}
+                // Every further request would be rate limited as well, so
+                // the remaining repos are indexed next time instead
+                Err(err) if is_rate_limited(&err) => {
+                    report::warn(format!("Failed to index `{}`: {:#}", name, err));
+                    break;
+                }
+                Err(err) => report::warn(format!("Failed to index `{}`: {:#}", name, err)),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fuzzy matches the query against script names and descriptions,
+    /// returning the results with the best match first
+    pub fn search(&self, query: &str) -> Vec<SearchResult<'_>> {
+        let matcher = SkimMatcherV2::default();
+
+        let mut results = self
+            .repos
+            .iter()
+            .flat_map(|(repo, indexed)| {
+                let rref = indexed.rref.as_str();
+                indexed.entries.iter().map(move |entry| (repo, rref, entry))
+            })
+            .filter_map(|(repo, rref, entry)| {
+                let name = format!("{}:{}", repo, entry.path);
+                let name_score = matcher.fuzzy_match(&name, query);
+                let description_score = entry
+                    .description
+                    .as_ref()
+                    .and_then(|description| matcher.fuzzy_match(description, query));
+
+                name_score.max(description_score).map(|score| SearchResult {
+                    repo,
+                    rref,
+                    entry,
+                    score,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        results.sort_by_key(|result| Reverse(result.score));
+        results
+    }
+}
+
+fn is_rate_limited(err: &anyhow::Error) -> bool {
+    matches!(err.downcast_ref(), Some(RemError::RateLimited(_)))
+}
+
+/// Lists the scripts in a repo and reads their descriptions. Scripts that
+/// can't be fetched are skipped, unless the provider's rate limit was hit.
+async fn index_repo(
+    name: &str,
+    repo: &dyn Repo,
+    rref: &str,
+    config: &Config,
+) -> Result<Vec<IndexEntry>> {
+    let cache = config.cache_policy(repo, false);
+    let files = repo.list_scripts("", rref, true, cache).await?;
+
+    let mut entries = Vec::new();
+    for file in files {
+        if file.is_dir || !config.is_script_name(&file.path) {
+            continue;
+        }
+
+        let contents = match repo.fetch_script(&file.path, rref, cache).await {
+            Ok(contents) => contents,
+            Err(err) if is_rate_limited(&err) => return Err(err),
+            Err(err) => {
+                report::warn(format!("Skipping `{}:{}`: {:#}", name, file.path, err));
+                continue;
+            }
+        };
+
+        entries.push(IndexEntry {
+            description: ScriptMeta::parse(&contents).description,
+            path: file.path,
+        });
+    }
+
+    Ok(entries)
+}
